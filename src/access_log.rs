@@ -0,0 +1,206 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap};
+use hyper::{Body, Request, Response, Version};
+use serde_json::json;
+use tracing::warn;
+
+use crate::config::{AccessLogConf, AccessLogFormat, Config};
+use crate::forwarded::client_ip;
+use crate::session::Claims;
+use crate::target::UpstreamLatency;
+
+/// The parts of a request that are logged, taken before it is handled
+pub struct Entry {
+    time: DateTime<Local>,
+    start: Instant,
+    request_id: String,
+    client_ip: Option<IpAddr>,
+    method: String,
+    uri: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|val| val.to_str().ok())
+        .map(ToOwned::to_owned)
+}
+
+impl Entry {
+    pub fn new(req: &Request<Body>, config: &Config, request_id: &str) -> Entry {
+        Entry {
+            time: Local::now(),
+            start: Instant::now(),
+            request_id: request_id.to_owned(),
+            client_ip: client_ip(req, &config.server.trusted_proxies),
+            method: req.method().to_string(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |pnq| pnq.as_str())
+                .to_owned(),
+            version: req.version(),
+            referer: header_str(req.headers(), header::REFERER),
+            user_agent: header_str(req.headers(), header::USER_AGENT),
+        }
+    }
+}
+
+/// The response body size when it is known up front
+fn body_bytes(resp: &Response<Body>) -> Option<u64> {
+    resp.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse().ok())
+        .or_else(|| resp.body().size_hint().exact())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Escape quotes, backslashes and control characters, so a value can't end
+/// its field or start a new line
+fn escape(val: &str) -> String {
+    let mut escaped = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02X}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quote a value for the combined format, `-` if it is missing
+fn quoted(val: Option<&str>) -> String {
+    match val {
+        Some(val) => format!("\"{}\"", escape(val)),
+        None => "\"-\"".to_owned(),
+    }
+}
+
+fn or_dash<T: ToString>(val: Option<T>) -> String {
+    val.map_or_else(|| "-".to_owned(), |val| val.to_string())
+}
+
+/// Lines waiting to be written. When the writer falls this far behind, lines
+/// are dropped rather than holding up requests.
+const QUEUE_LENGTH: usize = 8192;
+
+/// Write lines as they arrive, until the `AccessLog` sending them is dropped
+fn write_lines(lines: Receiver<String>, mut out: Box<dyn Write + Send>) {
+    for line in lines {
+        if let Err(err) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            warn!("error writing access log: {}", err);
+        }
+    }
+}
+
+/// Lines are formatted on the request path but written by a thread of their
+/// own, so a slow disk never blocks the runtime
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: SyncSender<String>,
+}
+
+impl AccessLog {
+    pub fn from_config(config: &AccessLogConf) -> Result<AccessLog> {
+        let out: Box<dyn Write + Send> = match &config.file {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("error opening access log: {}", path))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+
+        let (lines, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        std::thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || write_lines(receiver, out))
+            .context("error starting access log writer")?;
+
+        Ok(AccessLog {
+            format: config.format,
+            lines,
+        })
+    }
+
+    fn combined(entry: &Entry, resp: &Response<Body>) -> String {
+        let claims = resp.extensions().get::<Claims>();
+        let upstream = resp.extensions().get::<UpstreamLatency>();
+
+        format!(
+            "{} - {} [{}] \"{} {} {:?}\" {} {} {} {} {} {} {} {:.3}",
+            or_dash(entry.client_ip),
+            or_dash(claims.map(|claims| escape(&claims.subject).replace(' ', "_"))),
+            entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            entry.method,
+            entry.uri,
+            entry.version,
+            resp.status().as_u16(),
+            or_dash(body_bytes(resp)),
+            quoted(entry.referer.as_deref()),
+            quoted(entry.user_agent.as_deref()),
+            entry.request_id,
+            or_dash(claims.map(|claims| claims.issuer.as_str())),
+            or_dash(upstream.map(|latency| format!("{:.3}", millis(latency.0)))),
+            millis(entry.start.elapsed()),
+        )
+    }
+
+    fn json(entry: &Entry, resp: &Response<Body>) -> String {
+        let claims = resp.extensions().get::<Claims>();
+        let upstream = resp.extensions().get::<UpstreamLatency>();
+
+        json!({
+            "time": entry.time.to_rfc3339(),
+            "request_id": entry.request_id,
+            "client_ip": entry.client_ip,
+            "user": claims.map(|claims| &claims.subject),
+            "mechanism": claims.map(|claims| &claims.issuer),
+            "method": entry.method,
+            "uri": entry.uri,
+            "protocol": format!("{:?}", entry.version),
+            "status": resp.status().as_u16(),
+            "bytes": body_bytes(resp),
+            "referer": entry.referer,
+            "user_agent": entry.user_agent,
+            "upstream_ms": upstream.map(|latency| millis(latency.0)),
+            "duration_ms": millis(entry.start.elapsed()),
+        })
+        .to_string()
+    }
+
+    pub fn log(&self, entry: &Entry, resp: &Response<Body>) {
+        let mut line = match self.format {
+            AccessLogFormat::Combined => AccessLog::combined(entry, resp),
+            AccessLogFormat::Json => AccessLog::json(entry, resp),
+        };
+        line.push('\n');
+
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("access log writer is behind, dropped a line"),
+            Err(TrySendError::Disconnected(_)) => warn!("access log writer has stopped"),
+        }
+    }
+}
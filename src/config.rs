@@ -1,18 +1,235 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use jsonwebtoken::Algorithm;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
 use url::Url;
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// clients may present a certificate
+    Optional,
+    /// the handshake fails unless the client presents a valid certificate
+    Required,
+}
+
+fn default_client_auth() -> ClientAuth {
+    ClientAuth::Optional
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+fn default_min_version() -> TlsVersion {
+    TlsVersion::Tls12
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CertificateConf {
+    pub cert_file: String,
+    pub key_file: String,
+    /// file holding the password for an encrypted PKCS#8 key
+    pub key_password_file: Option<String>,
+    /// DER encoded OCSP response to staple, eg. from `openssl ocsp -respout`
+    pub ocsp_file: Option<String>,
+    /// names to serve this certificate for, from the certificate itself if empty
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TlsConfig {
+    /// the default certificate, used when no other certificate matches the SNI name
     pub cert_file: String,
     pub key_file: String,
+    pub key_password_file: Option<String>,
+    pub ocsp_file: Option<String>,
+    /// further certificates, selected by SNI name
+    #[serde(default)]
+    pub certificates: Vec<CertificateConf>,
+    /// CA certificates that client certificates are verified against
+    pub client_ca_file: Option<String>,
+    #[serde(default = "default_client_auth")]
+    pub client_auth: ClientAuth,
+    /// The settings below are only read at startup
+    #[serde(default = "default_min_version")]
+    pub min_version: TlsVersion,
+    /// cipher suites by their IANA name, all suites supported by rustls if empty
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// protocols offered by ALPN (`h2` and/or `http/1.1`), no ALPN if empty
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+    /// cache sessions on the server so clients can resume them
+    #[serde(default = "default_true")]
+    pub session_cache: bool,
+    /// issue TLS session tickets, encrypted with keys that rotate every 12 hours
+    #[serde(default)]
+    pub session_tickets: bool,
+}
+
+fn default_strip_header_prefixes() -> Vec<String> {
+    vec!["X-Seal-".to_owned()]
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_bind() -> String {
+    "0.0.0.0:8000".to_owned()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// the Apache/nginx combined format, followed by the request ID, login
+    /// mechanism and upstream latency in milliseconds
+    Combined,
+    /// one JSON object per line
+    Json,
+}
+
+fn default_access_log_format() -> AccessLogFormat {
+    AccessLogFormat::Combined
+}
+
+/// A line for each proxied request, separate from the application logs
+#[derive(Deserialize, Debug)]
+pub struct AccessLogConf {
+    #[serde(default = "default_access_log_format")]
+    pub format: AccessLogFormat,
+    /// file to append to, stdout if unset
+    pub file: Option<String>,
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_owned()
+}
+
+/// Requests whose path matches are labelled with the route name in metrics,
+/// the first matching route is used
+#[derive(Deserialize, Debug)]
+pub struct MetricsRoute {
+    pub name: String,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub not_paths: Vec<String>,
+}
+
+/// Prometheus metrics, answered by sealproxy itself like the health endpoints
+#[derive(Deserialize, Debug)]
+pub struct MetricsConf {
+    #[serde(default = "default_metrics_path")]
+    pub path: String,
+    /// metrics are only served on their own address, never on the proxy
+    /// listeners. May be the same address as the health endpoints. Only read
+    /// at startup.
+    pub bind: String,
+    #[serde(default)]
+    pub routes: Vec<MetricsRoute>,
+}
+
+fn default_health_path_prefix() -> String {
+    "/_seal".to_owned()
+}
+
+fn default_health_timeout() -> u64 {
+    2
+}
+
+fn default_health_cache() -> u64 {
+    5
+}
+
+/// Liveness and readiness endpoints at `{path_prefix}/live` and `{path_prefix}/ready`.
+/// They are answered by sealproxy itself, never by the filters or the target.
+#[derive(Deserialize, Debug)]
+pub struct HealthConf {
+    #[serde(default = "default_health_path_prefix")]
+    pub path_prefix: String,
+    /// serve the endpoints on their own address instead of on every listener.
+    /// Only read at startup.
+    pub bind: Option<String>,
+    /// readiness also requires the target to answer an HTTP request
+    #[serde(default)]
+    pub check_target: bool,
+    /// readiness also requires each LDAP user base to accept a connection
+    #[serde(default)]
+    pub check_user_base: bool,
+    /// seconds to wait for each readiness check
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    /// seconds that the target and user base checks are reused for, so that
+    /// probes don't turn into a request to each of them
+    #[serde(default = "default_health_cache")]
+    pub cache: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Listener {
+    /// address to listen on, when not listening on a Unix socket
+    pub bind: Option<String>,
+    /// path of a Unix domain socket to listen on
+    pub unix: Option<String>,
+    /// use `server.tls`
+    #[serde(default)]
+    pub tls: bool,
+    /// answer every request with a redirect to HTTPS
+    #[serde(default)]
+    pub redirect_https: bool,
+    /// port to redirect to, the default HTTPS port if unset
+    pub https_port: Option<u16>,
+    /// expect a PROXY protocol (v1 or v2) header on every connection, and take
+    /// the client address from it
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// on a Unix socket, trust `X-Forwarded-For` and similar headers from the
+    /// process that connects, as `server.trusted_proxies` does for TCP peers
+    #[serde(default)]
+    pub trusted_proxy: bool,
+    /// filters for requests on this listener, instead of the top level filters
+    pub filters: Option<Vec<FilterConf>>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Server {
     pub bind: Option<String>,
     pub tls: Option<TlsConfig>,
+    /// seconds to keep serving after SIGTERM, with readiness failing, so that
+    /// load balancers stop sending new requests
+    #[serde(default)]
+    pub shutdown_delay: u64,
+    /// seconds to wait for in-flight requests and upgraded connections to finish
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// Listeners are only bound at startup, a reload only changes the filters
+    /// of the listeners that are already bound. If none are given a single
+    /// listener is made from `bind` and `tls`.
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    pub health: Option<HealthConf>,
+    pub metrics: Option<MetricsConf>,
+    pub access_log: Option<AccessLogConf>,
+    /// headers removed from client requests before the filters run
+    #[serde(default)]
+    pub strip_headers: Vec<String>,
+    #[serde(default = "default_strip_header_prefixes")]
+    pub strip_header_prefixes: Vec<String>,
+    /// proxies allowed to set `X-Forwarded-For` and similar headers
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// hosts other than this one that login and logout may redirect to
+    #[serde(default)]
+    pub allowed_redirect_hosts: Vec<String>,
 }
 
 // #[derive(Deserialize, Debug)]
@@ -27,11 +244,25 @@ pub struct LdapConf {
     pub url: Url,
     pub base_dn: String,
     pub user_attr: Option<String>,
+    /// attribute of the user listing the DNs of their groups, `memberOf` if unset
+    pub group_attr: Option<String>,
+    /// search for groups under this DN as well, eg. for servers without `memberOf`
+    pub group_base_dn: Option<String>,
+    /// filter for the group search, `{dn}` is replaced with the user's DN
+    #[serde(default = "default_group_filter")]
+    pub group_filter: String,
+}
+
+fn default_group_filter() -> String {
+    "(member={dn})".to_owned()
 }
 
 #[derive(Deserialize, Debug)]
 pub struct UserPassConf {
     pub users: Vec<(String, String)>,
+    /// the users in each group
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -41,9 +272,43 @@ pub enum UserBaseConf {
     UserPass(UserPassConf),
 }
 
+fn default_identity_header() -> String {
+    "X-Seal-Identity".to_owned()
+}
+
+fn default_identity_lifetime() -> i64 {
+    60
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IdentityTokenConf {
+    #[serde(default = "default_identity_header")]
+    pub header: String,
+    pub audience: Option<String>,
+    /// lifetime of the token in seconds
+    #[serde(default = "default_identity_lifetime")]
+    pub lifetime: i64,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Target {
     pub url: Url,
+    #[serde(default = "default_true")]
+    pub identity_headers: bool,
+    pub identity_token: Option<IdentityTokenConf>,
+    #[serde(default = "default_true")]
+    pub strip_authorization: bool,
+    #[serde(default = "default_true")]
+    pub strip_session_cookie: bool,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    #[serde(default = "default_true")]
+    pub forwarded_headers: bool,
+    #[serde(default = "default_true")]
+    pub forwarded: bool,
+    /// send the target's host in the `Host` header instead of the client's
+    #[serde(default)]
+    pub rewrite_host: bool,
     //pub r#match: Match,
 }
 
@@ -72,6 +337,220 @@ pub struct RedirectFilterConf {
 #[derive(Deserialize, Debug)]
 pub struct CookieSessionFilterConf;
 
+fn default_jwks_path() -> String {
+    "/.well-known/jwks.json".to_owned()
+}
+
+fn default_discovery_path() -> String {
+    "/.well-known/openid-configuration".to_owned()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JwksFilterConf {
+    #[serde(default = "default_jwks_path")]
+    pub path: String,
+    #[serde(default = "default_discovery_path")]
+    pub discovery_path: String,
+}
+
+fn default_csrf_cookie_name() -> String {
+    "seal.csrf".to_owned()
+}
+
+fn default_csrf_header_name() -> String {
+    "X-CSRF-Token".to_owned()
+}
+
+fn default_csrf_form_field() -> String {
+    "csrf_token".to_owned()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CsrfFilterConf {
+    #[serde(default = "default_true")]
+    pub check_origin: bool,
+    /// reject unsafe requests with neither `Origin` nor `Referer`
+    #[serde(default)]
+    pub require_origin: bool,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub double_submit: bool,
+    #[serde(default = "default_csrf_cookie_name")]
+    pub cookie_name: String,
+    #[serde(default = "default_csrf_header_name")]
+    pub header_name: String,
+    #[serde(default = "default_csrf_form_field")]
+    pub form_field: String,
+    #[serde(default)]
+    pub exempt_paths: Vec<String>,
+}
+
+fn default_api_key_header() -> String {
+    "X-Api-Key".to_owned()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiKeyFilterConf {
+    /// YAML file of hashed keys, reloaded when it changes
+    pub key_file: String,
+    #[serde(default = "default_api_key_header")]
+    pub header: String,
+    /// query parameter that may carry the key, for clients that can't set headers
+    pub query_param: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CertField {
+    SubjectCn,
+    SanDns,
+    SanEmail,
+    SanUri,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CertRule {
+    pub field: CertField,
+    /// regex the value must match; the first capture group, if any, is the username
+    pub pattern: Option<String>,
+}
+
+fn default_cert_rules() -> Vec<CertRule> {
+    vec![CertRule {
+        field: CertField::SubjectCn,
+        pattern: None,
+    }]
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClientCertFilterConf {
+    /// rules tried in order to find the username in the certificate
+    #[serde(default = "default_cert_rules")]
+    pub rules: Vec<CertRule>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpAction {
+    /// let the request through without authentication
+    Allow,
+    /// reject the request with 403
+    Deny,
+    /// continue down the filter chain
+    Authenticate,
+}
+
+fn default_ip_otherwise() -> IpAction {
+    IpAction::Authenticate
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IpFilterConf {
+    /// paths the filter applies to, all paths if empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub not_paths: Vec<String>,
+    pub networks: Vec<IpNet>,
+    /// what to do when the client address is in `networks`
+    pub action: IpAction,
+    /// what to do when it isn't
+    #[serde(default = "default_ip_otherwise")]
+    pub otherwise: IpAction,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// the authenticated subject, or the client address for anonymous requests
+    Subject,
+    Ip,
+    /// the request path
+    Route,
+}
+
+fn default_rate_limit_max_keys() -> usize {
+    10000
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RateLimitFilterConf {
+    pub key: RateLimitKey,
+    /// requests per second added to each bucket
+    pub rate: f64,
+    /// the most requests that can be made at once
+    pub burst: u32,
+    /// buckets kept before idle ones are evicted
+    #[serde(default = "default_rate_limit_max_keys")]
+    pub max_keys: usize,
+    /// paths the filter applies to, all paths if empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub not_paths: Vec<String>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_owned(), "HEAD".to_owned(), "POST".to_owned()]
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CorsFilterConf {
+    /// origins allowed to make requests, or `*` for any origin (not with
+    /// `allow_credentials`)
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub exposed_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// seconds that browsers may cache a preflight response
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub not_paths: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderMode {
+    SetIfAbsent,
+    Override,
+}
+
+/// An empty value means the header is not sent
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct SecurityHeaders {
+    pub mode: Option<HeaderMode>,
+    pub strict_transport_security: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub x_frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SecurityHeadersRoute {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub not_paths: Vec<String>,
+    #[serde(flatten)]
+    pub headers: SecurityHeaders,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SecurityHeadersFilterConf {
+    #[serde(flatten)]
+    pub headers: SecurityHeaders,
+    #[serde(default)]
+    pub routes: Vec<SecurityHeadersRoute>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct BasicFilterConf {
     pub user_base: UserBaseConf,
@@ -89,16 +568,95 @@ pub struct FormLoginConf {
 #[serde(rename_all = "snake_case")]
 pub enum FilterConf {
     Anonymous(AnonymousFilterConf),
+    ApiKey(ApiKeyFilterConf),
     CookieSession(CookieSessionFilterConf),
     Basic(BasicFilterConf),
+    ClientCert(ClientCertFilterConf),
+    Cors(CorsFilterConf),
+    Csrf(CsrfFilterConf),
     FormLogin(FormLoginConf),
+    Ip(IpFilterConf),
+    Jwks(JwksFilterConf),
+    RateLimit(RateLimitFilterConf),
     Redirect(RedirectFilterConf),
+    SecurityHeaders(SecurityHeadersFilterConf),
+}
+
+impl Listener {
+    /// The bind address or unix socket path, which tells listeners apart
+    /// across reloads
+    pub fn name(&self) -> &str {
+        self.bind.as_deref().or(self.unix.as_deref()).unwrap_or_default()
+    }
+}
+
+impl FilterConf {
+    /// The name used for the filter in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterConf::Anonymous(_) => "anonymous",
+            FilterConf::ApiKey(_) => "api_key",
+            FilterConf::CookieSession(_) => "cookie_session",
+            FilterConf::Basic(_) => "basic",
+            FilterConf::ClientCert(_) => "client_cert",
+            FilterConf::Cors(_) => "cors",
+            FilterConf::Csrf(_) => "csrf",
+            FilterConf::FormLogin(_) => "form_login",
+            FilterConf::Ip(_) => "ip",
+            FilterConf::Jwks(_) => "jwks",
+            FilterConf::RateLimit(_) => "rate_limit",
+            FilterConf::Redirect(_) => "redirect",
+            FilterConf::SecurityHeaders(_) => "security_headers",
+        }
+    }
+}
+
+fn default_session_algorithm() -> Algorithm {
+    Algorithm::RS256
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RetiredSessionKey {
+    pub key_id: Option<String>,
+    pub algorithm: Option<Algorithm>,
+    pub public_key_file: Option<String>,
+    pub secret_file: Option<String>,
+    pub valid_until: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RetiredEncryptionKey {
+    pub key_id: String,
+    pub key_file: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionEncryption {
+    /// `kid` of the JWEs, which is configured since deriving it from the key
+    /// would publish a digest of the key
+    pub key_id: String,
+    pub key_file: String,
+    /// keys that tokens may still be encrypted with, for decryption only
+    #[serde(default)]
+    pub retired_keys: Vec<RetiredEncryptionKey>,
+    /// signed tokens that aren't encrypted are accepted until then, so that
+    /// turning encryption on doesn't end existing sessions. They are always
+    /// rejected if unset.
+    pub accept_unencrypted_until: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Session {
-    pub private_key_file: String,
-    pub public_key_file: String,
+    pub issuer: Option<String>,
+    #[serde(default = "default_session_algorithm")]
+    pub algorithm: Algorithm,
+    pub key_id: Option<String>,
+    pub private_key_file: Option<String>,
+    pub public_key_file: Option<String>,
+    pub secret_file: Option<String>,
+    #[serde(default)]
+    pub retired_keys: Vec<RetiredSessionKey>,
+    pub encryption: Option<SessionEncryption>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -113,7 +671,54 @@ pub fn load(path: &Path) -> Result<Config> {
     let reader = std::fs::File::open(path)
         .with_context(|| format!("Error loading config file: {}", path.to_string_lossy()))?;
 
-    let config = serde_yaml::from_reader(reader)?;
+    let mut config: Config = serde_yaml::from_reader(reader)?;
+
+    let server = &mut config.server;
+    if server.listeners.is_empty() {
+        server.listeners.push(Listener {
+            bind: Some(server.bind.take().unwrap_or_else(default_bind)),
+            unix: None,
+            tls: server.tls.is_some(),
+            redirect_https: false,
+            https_port: None,
+            proxy_protocol: false,
+            trusted_proxy: false,
+            filters: None,
+        });
+    } else if server.bind.is_some() {
+        anyhow::bail!("server.bind can't be used with server.listeners");
+    }
+
+    for listener in &server.listeners {
+        if listener.bind.is_some() == listener.unix.is_some() {
+            anyhow::bail!("each listener needs exactly one of bind or unix");
+        }
+        if listener.proxy_protocol && listener.unix.is_some() {
+            anyhow::bail!("proxy_protocol is only supported on TCP listeners");
+        }
+        if listener.tls && listener.unix.is_some() {
+            anyhow::bail!("tls is only supported on TCP listeners");
+        }
+        if listener.trusted_proxy && listener.unix.is_none() {
+            anyhow::bail!("trusted_proxy is only for unix listeners, use server.trusted_proxies");
+        }
+        if listener.tls && server.tls.is_none() {
+            anyhow::bail!("tls listeners need the server.tls settings");
+        }
+    }
+
+    if let Some(health) = &server.health {
+        if !health.path_prefix.starts_with('/') {
+            anyhow::bail!("health path_prefix must start with /");
+        }
+    }
+
+    // identity tokens are signed with the session key, so they must not be
+    // valid sessions
+    let audience = config.target.identity_token.as_ref().and_then(|token| token.audience.as_deref());
+    if audience == Some(crate::session::AUDIENCE) {
+        anyhow::bail!("identity_token audience can't be {:?}", crate::session::AUDIENCE);
+    }
 
     Ok(config)
 }
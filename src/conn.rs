@@ -0,0 +1,43 @@
+use rustls::Certificate;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// The other end of a connection
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// a process on this host, which has no address. It is only a trusted proxy
+    /// when the listener has `trusted_proxy` set.
+    Unix { trusted: bool },
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix { .. } => f.write_str("unix"),
+        }
+    }
+}
+
+/// Details of the connection a request arrived on. This is added to the
+/// extensions of every request before it reaches the filter chain.
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    pub peer: Peer,
+    pub tls: bool,
+    /// the client's certificate, which has been verified against `client_ca_file`
+    pub client_cert: Option<Arc<Certificate>>,
+    /// bind address or unix socket path of the listener, see `Listener::name`
+    pub listener: Arc<str>,
+}
@@ -1,49 +1,107 @@
 mod anonymous;
+mod api_key;
 mod basic;
+mod client_cert;
 mod cookie_session;
+mod cors;
+mod csrf;
 mod form_login;
+mod ip;
+mod jwks;
+mod rate_limit;
 mod redirect;
+mod security_headers;
 
+pub use api_key::reload_key_file;
 pub use basic::BasicFilter;
 
+use std::sync::atomic::{AtomicU8, Ordering};
+
 use anyhow::Result;
+use tracing::Instrument;
 use hyper::{client::HttpConnector, Client};
 use hyper::{Body, Request, Response, StatusCode};
 
 use crate::config::{Config, FilterConf};
 use crate::filters::anonymous::AnonymousFilter;
+use crate::filters::api_key::ApiKeyFilter;
+use crate::filters::client_cert::ClientCertFilter;
 use crate::filters::cookie_session::CookieSessionFilter;
+use crate::filters::cors::CorsFilter;
+use crate::filters::csrf::CsrfFilter;
 use crate::filters::form_login::FormLoginFilter;
+use crate::filters::ip::IpFilter;
+use crate::filters::jwks::JwksFilter;
+use crate::filters::rate_limit::RateLimitFilter;
 use crate::filters::redirect::RedirectFilter;
+use crate::filters::security_headers::SecurityHeadersFilter;
 use crate::session::Claims;
 use crate::state::State;
 
 type DynFilter = dyn Filter + Send + Sync + 'static;
 
+const PASSED: u8 = 1;
+const ALLOWED: u8 = 2;
+
+/// Records whether a filter passed the request on or allowed it through, for
+/// the filter decision metrics
+#[derive(Default)]
+struct Outcome(AtomicU8);
+
+impl Outcome {
+    fn set(&self, outcome: u8) {
+        self.0.store(outcome, Ordering::Relaxed);
+    }
+
+    /// Filters that answer the request themselves have redirected or rejected it
+    fn decision(&self, resp: &Result<Response<Body>>) -> &'static str {
+        match (self.0.load(Ordering::Relaxed), resp) {
+            (PASSED, _) => "passed",
+            (ALLOWED, _) => "allowed",
+            (_, Err(_)) => "error",
+            (_, Ok(resp)) if resp.status().is_redirection() => "redirected",
+            (_, Ok(resp)) if resp.status().is_success() => "allowed",
+            _ => "rejected",
+        }
+    }
+}
+
 pub struct Context<'a> {
     client: Client<HttpConnector>,
     state: &'a State,
-    rest: &'a [Box<DynFilter>],
+    rest: &'a [(&'static str, Box<DynFilter>)],
+    outcome: Option<&'a Outcome>,
 }
 
 impl<'a> Context<'a> {
-    pub fn new(state: &'a State) -> Self {
+    pub fn new(state: &'a State, chain: &'a FilterChain) -> Self {
         Context {
             state,
             client: state.client.clone(),
-            rest: state.filters.as_ref(),
+            rest: chain.as_ref(),
+            outcome: None,
         }
     }
 
     pub async fn next(self, req: Request<Body>) -> Result<Response<Body>> {
+        if let Some(outcome) = self.outcome {
+            outcome.set(PASSED);
+        }
+
         match self.rest.split_first() {
-            Some((head, rest)) => {
+            Some(((name, head), rest)) => {
+                let outcome = Outcome::default();
                 let ctx = Context {
                     state: self.state,
                     client: self.client,
                     rest,
+                    outcome: Some(&outcome),
                 };
-                head.apply(req, ctx).await
+
+                let span = tracing::info_span!("filter", otel.name = name, filter = name);
+                let resp = head.apply(req, ctx).instrument(span).await;
+                crate::metrics::filter_decision(name, outcome.decision(&resp));
+                resp
             }
             None => Ok(Response::builder()
                 .status(StatusCode::UNAUTHORIZED)
@@ -51,16 +109,41 @@ impl<'a> Context<'a> {
         }
     }
 
-    pub async fn finish(&self, req: Request<Body>) -> Result<Response<Body>> {
-        crate::target::route(req, &self.client, &self.state.config.target).await
+    pub async fn finish(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let decision = rate_limit::check_subject_limits(&req, &self.state.config);
+        if let Some(decision) = &decision {
+            if !decision.allowed {
+                return rate_limit::too_many_requests(decision);
+            }
+        }
+
+        if let Some(outcome) = self.outcome {
+            outcome.set(ALLOWED);
+        }
+
+        crate::target::add_identity(&mut req, self.state)?;
+        let claims = req.extensions().get::<Claims>().cloned();
+        let mut resp = crate::target::route(req, &self.client, &self.state.config).await?;
+
+        // for the access log
+        if let Some(claims) = claims {
+            resp.extensions_mut().insert(claims);
+        }
+
+        if let Some(decision) = &decision {
+            rate_limit::add_headers(resp.headers_mut(), decision);
+        }
+
+        Ok(resp)
     }
 
     pub fn establish_session(
         &self,
-        resp: Response<Body>,
+        mut resp: Response<Body>,
         claims: Claims,
     ) -> Result<Response<Body>> {
-        crate::session::establish_session(resp, claims, &self.state)
+        resp.extensions_mut().insert(claims.clone());
+        crate::session::establish_session(resp, claims, self.state)
     }
 }
 
@@ -70,41 +153,56 @@ pub trait Filter {
 }
 
 pub struct FilterChain {
-    filters: Vec<Box<DynFilter>>,
+    filters: Vec<(&'static str, Box<DynFilter>)>,
 }
 
 impl FilterChain {
     pub fn from_config(config: &Config) -> Result<FilterChain> {
+        FilterChain::from_filters(&config.filters)
+    }
+
+    pub fn from_filters(filters: &[FilterConf]) -> Result<FilterChain> {
         let mut chain = FilterChain { filters: vec![] };
 
-        for filter in &config.filters {
+        for filter in filters {
+            let name = filter.name();
             match filter {
                 FilterConf::Anonymous(config) => {
-                    chain.add(AnonymousFilter::new(config)?);
+                    chain.add(name, AnonymousFilter::new(config)?);
                 }
+                FilterConf::ApiKey(config) => chain.add(name, ApiKeyFilter::new(config)?),
                 FilterConf::Basic(config) => {
-                    chain.add(BasicFilter::new(config)?);
+                    chain.add(name, BasicFilter::new(config)?);
                 }
+                FilterConf::ClientCert(config) => chain.add(name, ClientCertFilter::new(config)?),
                 FilterConf::CookieSession(config) => {
-                    chain.add(CookieSessionFilter::new(config)?);
+                    chain.add(name, CookieSessionFilter::new(config)?);
                 }
+                FilterConf::Cors(config) => chain.add(name, CorsFilter::new(config)?),
+                FilterConf::Csrf(config) => chain.add(name, CsrfFilter::new(config)?),
                 FilterConf::FormLogin(config) => {
-                    chain.add(FormLoginFilter::new(config)?);
+                    chain.add(name, FormLoginFilter::new(config)?);
+                }
+                FilterConf::Ip(config) => chain.add(name, IpFilter::new(config)?),
+                FilterConf::Jwks(config) => chain.add(name, JwksFilter::new(config)?),
+                FilterConf::RateLimit(config) => chain.add(name, RateLimitFilter::new(config)?),
+                FilterConf::Redirect(config) => chain.add(name, RedirectFilter::new(config)?),
+                FilterConf::SecurityHeaders(config) => {
+                    chain.add(name, SecurityHeadersFilter::new(config)?);
                 }
-                FilterConf::Redirect(config) => chain.add(RedirectFilter::new(config)?),
             }
         }
 
         Ok(chain)
     }
 
-    pub fn add(&mut self, filter: impl Filter + Send + Sync + 'static) {
-        self.filters.push(Box::new(filter));
+    pub fn add(&mut self, name: &'static str, filter: impl Filter + Send + Sync + 'static) {
+        self.filters.push((name, Box::new(filter)));
     }
 }
 
-impl AsRef<[Box<DynFilter>]> for FilterChain {
-    fn as_ref(&self) -> &[Box<DynFilter>] {
+impl AsRef<[(&'static str, Box<DynFilter>)]> for FilterChain {
+    fn as_ref(&self) -> &[(&'static str, Box<DynFilter>)] {
         self.filters.as_slice()
     }
 }
@@ -0,0 +1,216 @@
+use crate::config::ApiKeyFilterConf;
+use crate::filters::{Context, Filter};
+use crate::metrics;
+use crate::session::Claims;
+use crate::target::add_header_claims;
+use anyhow::{Context as _, Result};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use hyper::header::HeaderName;
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{debug, info, trace};
+
+/// An entry in the key file. Only the SHA-256 hash of each key is stored, so
+/// the file doesn't need to be kept secret.
+#[derive(Deserialize)]
+struct ApiKeyEntry {
+    name: String,
+    owner: Option<String>,
+    /// hex encoded SHA-256 of the key
+    key_sha256: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    expires: Option<DateTime<Utc>>,
+}
+
+fn unauthorized() -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::empty())?)
+}
+
+fn hash_key(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+
+    let mut hex = String::with_capacity(64);
+    for byte in digest.as_ref() {
+        write!(hex, "{:02x}", byte).expect("writing to a string can't fail");
+    }
+    hex
+}
+
+/// Entries by the hash of their key
+type Keys = HashMap<String, ApiKeyEntry>;
+
+/// The keys loaded from each file, shared with the filters using them so that a
+/// changed key file is reloaded without rebuilding the rest of the state
+static KEY_FILES: Lazy<Mutex<HashMap<String, Weak<ArcSwap<Keys>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn load_keys(file: &str) -> Result<Keys> {
+    let reader = std::fs::File::open(file)
+        .with_context(|| format!("error reading api key file: {}", file))?;
+    let entries: Vec<ApiKeyEntry> = serde_yaml::from_reader(reader)
+        .with_context(|| format!("error parsing api key file: {}", file))?;
+
+    let mut keys = HashMap::new();
+    for entry in entries {
+        let hash = entry.key_sha256.to_ascii_lowercase();
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("api key {:?} has an invalid key_sha256", entry.name);
+        }
+
+        if keys.insert(hash, entry).is_some() {
+            anyhow::bail!("duplicate api key in {}", file);
+        }
+    }
+
+    Ok(keys)
+}
+
+/// The keys from `file`, shared with any other filter using the same file
+fn shared_keys(file: &str) -> Result<Arc<ArcSwap<Keys>>> {
+    let keys = load_keys(file)?;
+
+    let mut files = KEY_FILES.lock().expect("api key files lock poisoned");
+    files.retain(|_, shared| shared.strong_count() > 0);
+
+    match files.get(file).and_then(Weak::upgrade) {
+        Some(shared) => {
+            shared.store(Arc::new(keys));
+            Ok(shared)
+        }
+        None => {
+            let shared = Arc::new(ArcSwap::from_pointee(keys));
+            files.insert(file.to_owned(), Arc::downgrade(&shared));
+            Ok(shared)
+        }
+    }
+}
+
+/// Reload a key file for the filters using it. The rest of the state, such as
+/// rate limits, is left alone.
+pub fn reload_key_file(file: &str) -> Result<()> {
+    let keys = load_keys(file)?;
+
+    let files = KEY_FILES.lock().expect("api key files lock poisoned");
+    if let Some(shared) = files.get(file).and_then(Weak::upgrade) {
+        shared.store(Arc::new(keys));
+    }
+
+    Ok(())
+}
+
+/// Remove the key from the query string, so it isn't forwarded to the target.
+/// The rest of the query is kept exactly as the client sent it.
+fn strip_query_param(uri: &Uri, param: &str) -> Result<Uri> {
+    let query = match uri.query() {
+        Some(query) => query,
+        None => return Ok(uri.clone()),
+    };
+
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|segment| match url::form_urlencoded::parse(segment.as_bytes()).next() {
+            Some((name, _value)) => name != param,
+            None => true,
+        })
+        .collect();
+
+    let mut path_and_query = uri.path().to_owned();
+    if !kept.is_empty() {
+        path_and_query.push('?');
+        path_and_query.push_str(&kept.join("&"));
+    }
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+pub struct ApiKeyFilter {
+    keys: Arc<ArcSwap<Keys>>,
+    header: HeaderName,
+    query_param: Option<String>,
+}
+
+impl ApiKeyFilter {
+    pub fn new(config: &ApiKeyFilterConf) -> Result<Self> {
+        Ok(ApiKeyFilter {
+            keys: shared_keys(&config.key_file)?,
+            header: config.header.parse()?,
+            query_param: config.query_param.clone(),
+        })
+    }
+
+    /// Take the key from the request, removing it so the target never sees it
+    fn take_key(&self, req: &mut Request<Body>) -> Result<Option<String>> {
+        if let Some(val) = req.headers_mut().remove(&self.header) {
+            return Ok(Some(val.to_str()?.to_owned()));
+        }
+
+        if let Some(param) = &self.query_param {
+            let key = req.uri().query().and_then(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(k, _v)| k == param)
+                    .map(|(_k, v)| v.into_owned())
+            });
+
+            if key.is_some() {
+                *req.uri_mut() = strip_query_param(req.uri(), param)?;
+            }
+
+            return Ok(key);
+        }
+
+        Ok(None)
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for ApiKeyFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, mut req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        let key = match self.take_key(&mut req)? {
+            Some(key) => key,
+            None => {
+                trace!("no api key provided");
+                return ctx.next(req).await;
+            }
+        };
+
+        let keys = self.keys.load_full();
+        let entry = match keys.get(&hash_key(&key)) {
+            Some(entry) => entry,
+            None => {
+                debug!("unknown api key");
+                metrics::login("api_key", "none", "failure");
+                return unauthorized();
+            }
+        };
+
+        if entry.expires.is_some_and(|expires| expires <= Utc::now()) {
+            debug!(name = %entry.name, "expired api key");
+            metrics::login("api_key", "none", "failure");
+            return unauthorized();
+        }
+
+        info!(name = %entry.name, owner = ?entry.owner, "api key accepted");
+        metrics::login("api_key", "none", "success");
+
+        let claims = Claims {
+            issuer: "seal/apikey".to_owned(),
+            subject: entry.name.clone(),
+            scopes: entry.scopes.clone(),
+            ..Default::default()
+        };
+        add_header_claims(&mut req, claims)?;
+
+        ctx.finish(req).await
+    }
+}
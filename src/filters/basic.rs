@@ -1,7 +1,8 @@
 use crate::config::BasicFilterConf;
 use crate::filters::{Context, Filter};
+use crate::metrics;
 use crate::session::Claims;
-use crate::target::add_header_claims;
+use crate::target::{add_header_claims, AuthorizationUsed};
 use crate::userbase::{get_user_base, DynUserBase, LookupResult};
 use anyhow::Result;
 use hyper::header;
@@ -34,7 +35,7 @@ struct BasicAuth {
 
 fn get_basic_auth(req: &Request<Body>) -> Result<Option<BasicAuth>> {
     // TODO - fix this horrible if let cascade
-    return if let Some(authn) = req.headers().get(header::AUTHORIZATION) {
+    if let Some(authn) = req.headers().get(header::AUTHORIZATION) {
         trace!("got Authorization header");
 
         if let Some(("Basic", userpass)) = authn.to_str()?.split_once(" ") {
@@ -60,7 +61,7 @@ fn get_basic_auth(req: &Request<Body>) -> Result<Option<BasicAuth>> {
     } else {
         trace!("Authorization header not present");
         Ok(None)
-    };
+    }
 }
 
 #[async_trait::async_trait]
@@ -68,11 +69,13 @@ impl Filter for BasicFilter {
     #[tracing::instrument(skip(self, req, ctx))]
     async fn apply(&self, mut req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
         if let Some(basic_auth) = get_basic_auth(&req)? {
-            return match self
+            let result = self
                 .user_base
                 .lookup(&basic_auth.username, &basic_auth.password)
-                .await?
-            {
+                .await;
+            metrics::user_base_login("basic", self.user_base.as_ref(), &result);
+
+            return match result? {
                 LookupResult::NoSuchUser => {
                     debug!("user not found");
                     unauthorized()
@@ -81,15 +84,18 @@ impl Filter for BasicFilter {
                     debug!("incorrect password");
                     unauthorized()
                 }
-                LookupResult::Success => {
+                LookupResult::Success { groups } => {
                     info!("successful basic auth login");
 
                     let claims = Claims {
                         issuer: "seal/basic".to_owned(),
                         subject: basic_auth.username.clone(),
+                        groups,
+                        ..Default::default()
                     };
 
                     add_header_claims(&mut req, claims.clone())?;
+                    req.extensions_mut().insert(AuthorizationUsed);
 
                     let resp = ctx.finish(req).await?;
                     ctx.establish_session(resp, claims)
@@ -0,0 +1,131 @@
+use crate::config::{CertField, ClientCertFilterConf};
+use crate::conn::ConnInfo;
+use crate::filters::{Context, Filter};
+use crate::metrics;
+use crate::session::Claims;
+use crate::target::add_header_claims;
+use anyhow::{anyhow, Result};
+use hyper::{Body, Request, Response};
+use regex::Regex;
+use tracing::{debug, info, trace};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+
+struct Rule {
+    field: CertField,
+    pattern: Option<Regex>,
+}
+
+impl Rule {
+    fn username(&self, value: &str) -> Option<String> {
+        match &self.pattern {
+            None => Some(value.to_owned()),
+            Some(pattern) => pattern.captures(value).map(|caps| {
+                caps.get(1)
+                    .or_else(|| caps.get(0))
+                    .map_or("", |m| m.as_str())
+                    .to_owned()
+            }),
+        }
+    }
+}
+
+fn field_values<'a>(cert: &'a X509Certificate, field: CertField) -> Result<Vec<&'a str>> {
+    if field == CertField::SubjectCn {
+        return cert
+            .subject()
+            .iter_common_name()
+            .map(|cn| cn.as_str().map_err(|e| anyhow!("invalid certificate CN: {}", e)))
+            .collect();
+    }
+
+    let san = match cert.subject_alternative_name()? {
+        Some(san) => san,
+        None => return Ok(vec![]),
+    };
+
+    Ok(san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match (field, name) {
+            (CertField::SanDns, GeneralName::DNSName(value))
+            | (CertField::SanEmail, GeneralName::RFC822Name(value))
+            | (CertField::SanUri, GeneralName::URI(value)) => Some(*value),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Authenticates clients by the certificate they presented in the TLS handshake.
+/// The certificate has already been verified against `server.tls.client_ca_file`.
+pub struct ClientCertFilter {
+    rules: Vec<Rule>,
+}
+
+impl ClientCertFilter {
+    pub fn new(config: &ClientCertFilterConf) -> Result<Self> {
+        let mut rules = vec![];
+        for rule in &config.rules {
+            rules.push(Rule {
+                field: rule.field,
+                pattern: rule.pattern.as_deref().map(Regex::new).transpose()?,
+            });
+        }
+
+        Ok(ClientCertFilter { rules })
+    }
+
+    fn username(&self, der: &[u8]) -> Result<Option<String>> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|e| anyhow!("error parsing client certificate: {}", e))?;
+
+        for rule in &self.rules {
+            for value in field_values(&cert, rule.field)? {
+                if let Some(username) = rule.username(value) {
+                    return Ok(Some(username));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for ClientCertFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, mut req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        let cert = match req.extensions().get::<ConnInfo>() {
+            Some(ConnInfo {
+                client_cert: Some(cert),
+                ..
+            }) => cert.clone(),
+            _ => {
+                trace!("no client certificate");
+                return ctx.next(req).await;
+            }
+        };
+
+        match self.username(&cert.0)? {
+            Some(username) if !username.is_empty() => {
+                info!(%username, "client certificate accepted");
+                metrics::login("client_cert", "none", "success");
+
+                let claims = Claims {
+                    issuer: "seal/clientcert".to_owned(),
+                    subject: username,
+                    ..Default::default()
+                };
+                add_header_claims(&mut req, claims)?;
+
+                ctx.finish(req).await
+            }
+            _ => {
+                debug!("no username found in client certificate");
+                metrics::login("client_cert", "none", "failure");
+                ctx.next(req).await
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::config::CorsFilterConf;
+use crate::filters::{Context, Filter};
+use crate::path_match::PathMatch;
+use anyhow::Result;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tracing::{debug, trace};
+
+/// Answers CORS preflight requests without passing them down the chain (they
+/// never carry credentials) and adds CORS headers to responses for allowed origins.
+pub struct CorsFilter {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<u64>,
+    matcher: Option<PathMatch>,
+}
+
+impl CorsFilter {
+    pub fn new(config: &CorsFilterConf) -> Result<Self> {
+        // browsers refuse this combination, and echoing the origin instead would
+        // let any site make credentialed requests
+        if config.allow_credentials && config.allowed_origins.iter().any(|o| o == "*") {
+            anyhow::bail!("cors: allow_credentials can't be used with the \"*\" origin");
+        }
+
+        let mut allowed_methods = vec![];
+        for method in &config.allowed_methods {
+            allowed_methods.push(method.parse()?);
+        }
+
+        let matcher = if config.paths.is_empty() {
+            None
+        } else {
+            Some(PathMatch::new(&config.paths, &config.not_paths)?)
+        };
+
+        Ok(CorsFilter {
+            allowed_origins: config.allowed_origins.clone(),
+            allowed_methods,
+            allowed_headers: config
+                .allowed_headers
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            exposed_headers: config.exposed_headers.clone(),
+            allow_credentials: config.allow_credentials,
+            max_age: config.max_age,
+            matcher,
+        })
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn headers_allowed(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .all(|name| self.allowed_headers.iter().any(|allowed| allowed == "*" || *allowed == name))
+    }
+
+    fn add_origin_headers(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        // `*` is never combined with credentials, see `new`
+        let wildcard = self.allowed_origins.iter().any(|o| o == "*");
+
+        if wildcard {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight(&self, req: &Request<Body>, origin: &HeaderValue) -> Result<Response<Body>> {
+        let method_allowed = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<Method>().ok())
+            .is_some_and(|method| self.allowed_methods.contains(&method));
+
+        let headers_allowed = match req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            Some(val) => self.headers_allowed(val.to_str()?),
+            None => true,
+        };
+
+        if !method_allowed || !headers_allowed {
+            debug!(method_allowed, headers_allowed, "rejecting cors preflight");
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())?);
+        }
+
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?;
+        let headers = resp.headers_mut();
+
+        self.add_origin_headers(headers, origin);
+
+        let methods: Vec<&str> = self.allowed_methods.iter().map(Method::as_str).collect();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_str(&methods.join(", "))?,
+        );
+
+        // echo the requested headers, they have been checked against the allowed list
+        if let Some(requested) = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+
+        Ok(resp)
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for CorsFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        if let Some(matcher) = &self.matcher {
+            if !matcher.matches(req.uri().path())? {
+                return ctx.next(req).await;
+            }
+        }
+
+        let origin = match req.headers().get(header::ORIGIN) {
+            Some(origin) if self.origin_allowed(origin.to_str()?) => origin.clone(),
+            _ => return ctx.next(req).await,
+        };
+
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+        if is_preflight {
+            trace!("answering cors preflight");
+            return self.preflight(&req, &origin);
+        }
+
+        let mut resp = ctx.next(req).await?;
+        let headers = resp.headers_mut();
+
+        self.add_origin_headers(headers, &origin);
+
+        if !self.exposed_headers.is_empty() {
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_str(&self.exposed_headers.join(", "))?,
+            );
+        }
+
+        Ok(resp)
+    }
+}
@@ -0,0 +1,267 @@
+use crate::config::CsrfFilterConf;
+use crate::filters::{Context, Filter};
+use crate::forwarded::request_scheme;
+use crate::path_match::PathMatch;
+use anyhow::{anyhow, Result};
+use cookie::{Cookie, SameSite};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use ipnet::IpNet;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{debug, trace};
+use url::Url;
+
+pub struct CsrfFilter {
+    check_origin: bool,
+    require_origin: bool,
+    allowed_origins: Vec<String>,
+    double_submit: bool,
+    cookie_name: String,
+    header_name: String,
+    form_field: String,
+    exempt: PathMatch,
+    rng: SystemRandom,
+}
+
+/// The largest form that is read looking for the token
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+fn forbidden() -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())?)
+}
+
+#[derive(Debug)]
+struct FormTooLarge;
+
+impl std::fmt::Display for FormTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "form is larger than {} bytes", MAX_FORM_SIZE)
+    }
+}
+
+impl std::error::Error for FormTooLarge {}
+
+/// Buffer the body, failing with `FormTooLarge` rather than reading more than
+/// `MAX_FORM_SIZE` bytes from an unauthenticated client
+async fn read_form(req: &mut Request<Body>) -> Result<Vec<u8>> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<u64>().ok());
+    if length.is_some_and(|length| length > MAX_FORM_SIZE as u64) {
+        return Err(FormTooLarge.into());
+    }
+
+    let mut body = vec![];
+    while let Some(chunk) = req.body_mut().data().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_FORM_SIZE {
+            return Err(FormTooLarge.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// The origin (scheme, host and port) that sent the request, from `Origin`
+/// or failing that `Referer`
+fn request_origin(req: &Request<Body>) -> Result<Option<String>> {
+    let source = req
+        .headers()
+        .get(header::ORIGIN)
+        .or_else(|| req.headers().get(header::REFERER));
+
+    match source {
+        Some(val) => {
+            // unparseable values such as `Origin: null` never match, so are rejected
+            let val = val.to_str()?;
+            Ok(Some(Url::parse(val).map_or_else(
+                |_| val.to_owned(),
+                |url| url.origin().ascii_serialization(),
+            )))
+        }
+        None => Ok(None),
+    }
+}
+
+impl CsrfFilter {
+    pub fn new(config: &CsrfFilterConf) -> Result<Self> {
+        Ok(CsrfFilter {
+            check_origin: config.check_origin,
+            require_origin: config.require_origin,
+            allowed_origins: config.allowed_origins.clone(),
+            double_submit: config.double_submit,
+            cookie_name: config.cookie_name.clone(),
+            header_name: config.header_name.clone(),
+            form_field: config.form_field.clone(),
+            exempt: PathMatch::new(&config.exempt_paths, &[])?,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn origin_allowed(&self, req: &Request<Body>, trusted_proxies: &[IpNet]) -> Result<bool> {
+        let origin = match request_origin(req)? {
+            Some(origin) => origin,
+            None => {
+                // clients other than browsers generally don't send either header
+                trace!("no Origin or Referer");
+                return Ok(!self.require_origin);
+            }
+        };
+
+        if self.allowed_origins.iter().any(|allowed| allowed == &origin) {
+            return Ok(true);
+        }
+
+        let scheme = request_scheme(req, trusted_proxies);
+
+        let same_origin = match req.headers().get(header::HOST) {
+            Some(host) => {
+                let own = Url::parse(&format!("{}://{}", scheme, host.to_str()?))?;
+                own.origin().ascii_serialization() == origin
+            }
+            None => false,
+        };
+
+        if !same_origin {
+            debug!(%origin, "cross origin request");
+        }
+
+        Ok(same_origin)
+    }
+
+    fn cookie_token(&self, req: &Request<Body>) -> Option<String> {
+        req.headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(';'))
+            .filter_map(|c| Cookie::parse(c.trim()).ok())
+            .find(|c| c.name() == self.cookie_name)
+            .map(|c| c.value().to_owned())
+    }
+
+    /// The token submitted with the request, from the header or, for urlencoded
+    /// forms, the form field. Reading the form buffers the body, so it is put back
+    /// onto the request afterwards.
+    async fn submitted_token(&self, req: &mut Request<Body>) -> Result<Option<String>> {
+        if let Some(val) = req.headers().get(self.header_name.as_str()) {
+            return Ok(Some(val.to_str()?.to_owned()));
+        }
+
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<mime::Mime>().ok())
+            .is_some_and(|mime| {
+                mime.essence_str() == mime::APPLICATION_WWW_FORM_URLENCODED.essence_str()
+            });
+
+        if !is_form {
+            return Ok(None);
+        }
+
+        let body = read_form(req).await?;
+        let token = url::form_urlencoded::parse(&body)
+            .find(|(k, _v)| *k == self.form_field)
+            .map(|(_k, v)| v.into_owned());
+        *req.body_mut() = Body::from(body);
+
+        Ok(token)
+    }
+
+    async fn token_valid(&self, req: &mut Request<Body>) -> Result<bool> {
+        let expected = match self.cookie_token(req) {
+            Some(token) => token,
+            None => {
+                debug!("no csrf cookie");
+                return Ok(false);
+            }
+        };
+
+        Ok(match self.submitted_token(req).await? {
+            Some(token) => {
+                ring::constant_time::verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+                    .is_ok()
+            }
+            None => {
+                debug!("no csrf token submitted");
+                false
+            }
+        })
+    }
+
+    fn set_cookie(&self, resp: &mut Response<Body>, secure: bool) -> Result<()> {
+        let mut token = [0; 32];
+        self.rng
+            .fill(&mut token)
+            .map_err(|_| anyhow!("error generating csrf token"))?;
+
+        // not http only, so that scripts can copy the token into a header
+        let cookie = Cookie::build(
+            self.cookie_name.as_str(),
+            base64::encode_config(token, base64::URL_SAFE_NO_PAD),
+        )
+        .path("/")
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .finish();
+
+        let header = HeaderValue::from_str(cookie.to_string().as_ref())?;
+        resp.headers_mut().append(header::SET_COOKIE, header);
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for CsrfFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, mut req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        let unsafe_method = !req.method().is_safe();
+        let exempt = self.exempt.matches(req.uri().path())?;
+
+        let trusted_proxies = &ctx.state.config.server.trusted_proxies;
+
+        if unsafe_method && !exempt {
+            if self.check_origin && !self.origin_allowed(&req, trusted_proxies)? {
+                debug!("rejecting cross origin request");
+                return forbidden();
+            }
+
+            if self.double_submit {
+                let valid = match self.token_valid(&mut req).await {
+                    Err(err) if err.is::<FormTooLarge>() => {
+                        debug!("rejecting csrf form: {}", err);
+                        return Ok(Response::builder()
+                            .status(StatusCode::PAYLOAD_TOO_LARGE)
+                            .body(Body::empty())?);
+                    }
+                    res => res?,
+                };
+                if !valid {
+                    debug!("rejecting request without a valid csrf token");
+                    return forbidden();
+                }
+            }
+        }
+
+        let needs_cookie = self.double_submit && self.cookie_token(&req).is_none();
+        let secure = request_scheme(&req, trusted_proxies) == "https";
+
+        let mut resp = ctx.next(req).await?;
+
+        if needs_cookie {
+            trace!("issuing csrf cookie");
+            self.set_cookie(&mut resp, secure)?;
+        }
+
+        Ok(resp)
+    }
+}
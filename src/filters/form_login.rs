@@ -1,5 +1,7 @@
 use crate::config::FormLoginConf;
 use crate::filters::{Context, Filter};
+use crate::metrics;
+use crate::safe_redirect::safe_redirect;
 use crate::session::Claims;
 use crate::userbase::{get_user_base, DynUserBase, LookupResult};
 use anyhow::Result;
@@ -53,11 +55,11 @@ impl Filter for FormLoginFilter {
             return ctx.next(req).await;
         }
 
-        match req.method() {
-            &Method::POST => {
+        match *req.method() {
+            Method::POST => {
                 trace!("post to login path");
             }
-            &Method::GET => {
+            Method::GET => {
                 // GET is passed to the backend to serve up the login page
                 return ctx.finish(req).await;
             }
@@ -72,17 +74,21 @@ impl Filter for FormLoginFilter {
 
         let form: Form = serde_urlencoded::from_bytes(body.as_ref())?;
 
-        match self
+        let result = self
             .user_base
             .lookup(&form.username, &form.password)
-            .await?
-        {
-            LookupResult::Success => {
+            .await;
+        metrics::user_base_login("form_login", self.user_base.as_ref(), &result);
+
+        match result? {
+            LookupResult::Success { groups } => {
                 info!("successful form login");
 
                 let claims = Claims {
                     issuer: "seal/formlogin".to_owned(),
                     subject: form.username.clone(),
+                    groups,
+                    ..Default::default()
                 };
 
                 let ret= req.uri().query().and_then(|q|
@@ -92,13 +98,14 @@ impl Filter for FormLoginFilter {
                         .map(|(_k, v)| v.into_owned())
                 );
 
-                let redirect = ret
-                    .or_else(|| self.success_redirect.clone())
-                    .unwrap_or_else(|| "/".to_owned());
+                let redirect = safe_redirect(
+                    [ret.as_deref(), self.success_redirect.as_deref()],
+                    &ctx.state.config.server.allowed_redirect_hosts,
+                );
 
                 let resp = Response::builder()
                     .status(StatusCode::SEE_OTHER)
-                    .header(header::LOCATION, &redirect)
+                    .header(header::LOCATION, redirect)
                     .body(Body::empty())?;
 
                 ctx.establish_session(resp, claims)
@@ -0,0 +1,63 @@
+use crate::config::{IpAction, IpFilterConf};
+use crate::filters::{Context, Filter};
+use crate::forwarded::client_ip;
+use crate::path_match::PathMatch;
+use anyhow::Result;
+use hyper::{Body, Request, Response, StatusCode};
+use ipnet::IpNet;
+use tracing::{debug, trace};
+
+pub struct IpFilter {
+    matcher: Option<PathMatch>,
+    networks: Vec<IpNet>,
+    action: IpAction,
+    otherwise: IpAction,
+}
+
+impl IpFilter {
+    pub fn new(config: &IpFilterConf) -> Result<Self> {
+        let matcher = if config.paths.is_empty() {
+            None
+        } else {
+            Some(PathMatch::new(&config.paths, &config.not_paths)?)
+        };
+
+        Ok(IpFilter {
+            matcher,
+            networks: config.networks.clone(),
+            action: config.action,
+            otherwise: config.otherwise,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for IpFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        if let Some(matcher) = &self.matcher {
+            if !matcher.matches(req.uri().path())? {
+                return ctx.next(req).await;
+            }
+        }
+
+        let client = client_ip(&req, &ctx.state.config.server.trusted_proxies);
+        let listed = client.is_some_and(|ip| self.networks.iter().any(|net| net.contains(&ip)));
+
+        let action = if listed { self.action } else { self.otherwise };
+
+        match action {
+            IpAction::Allow => {
+                trace!(?client, "allowing client address");
+                ctx.finish(req).await
+            }
+            IpAction::Deny => {
+                debug!(?client, "denying client address");
+                Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::empty())?)
+            }
+            IpAction::Authenticate => ctx.next(req).await,
+        }
+    }
+}
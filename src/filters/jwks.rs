@@ -0,0 +1,87 @@
+use crate::config::JwksFilterConf;
+use crate::filters::{Context, Filter};
+use anyhow::Result;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use jsonwebtoken::Algorithm;
+use serde::Serialize;
+use tracing::trace;
+
+#[derive(Serialize)]
+struct Discovery {
+    issuer: String,
+    jwks_uri: String,
+    id_token_signing_alg_values_supported: Vec<Algorithm>,
+}
+
+/// Publishes the session public keys so upstreams can verify tokens signed by
+/// sealproxy themselves. The discovery document is only served when the session
+/// `issuer` is configured.
+pub struct JwksFilter {
+    path: String,
+    discovery_path: String,
+}
+
+impl JwksFilter {
+    pub fn new(config: &JwksFilterConf) -> Result<Self> {
+        Ok(JwksFilter {
+            path: config.path.clone(),
+            discovery_path: config.discovery_path.clone(),
+        })
+    }
+
+    fn json(body: &impl Serialize) -> Result<Response<Body>> {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "public, max-age=300")
+            .body(Body::from(serde_json::to_vec(body)?))?)
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for JwksFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        let path = req.uri().path();
+        let session = &ctx.state.config.session;
+
+        let serve_discovery = path == self.discovery_path && session.issuer.is_some();
+        if path != self.path && !serve_discovery {
+            return ctx.next(req).await;
+        }
+
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())?);
+        }
+
+        let keys = &ctx.state.session_keys;
+
+        match &session.issuer {
+            Some(issuer) if serve_discovery => {
+                trace!("serving discovery document");
+
+                // retired keys that are still published may use other algorithms
+                let mut algorithms = vec![keys.algorithm()];
+                for jwk in keys.jwks().keys {
+                    if let Some(alg) = jwk.common.algorithm {
+                        if !algorithms.contains(&alg) {
+                            algorithms.push(alg);
+                        }
+                    }
+                }
+
+                Self::json(&Discovery {
+                    issuer: issuer.clone(),
+                    jwks_uri: format!("{}{}", issuer.trim_end_matches('/'), self.path),
+                    id_token_signing_alg_values_supported: algorithms,
+                })
+            }
+            _ => {
+                trace!("serving jwks");
+                Self::json(&keys.jwks())
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use crate::config::{Config, RateLimitFilterConf, RateLimitKey};
+use crate::filters::{Context, Filter};
+use crate::forwarded::client_ip;
+use crate::path_match::PathMatch;
+use crate::session::Claims;
+use anyhow::Result;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::debug;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The outcome of taking a token from a bucket
+pub struct Decision {
+    pub allowed: bool,
+    limit: u32,
+    remaining: u32,
+    /// seconds until the bucket is full again
+    reset: u64,
+    /// seconds until the next token is available
+    retry_after: u64,
+}
+
+struct Limiter {
+    rate: f64,
+    burst: u32,
+    max_keys: usize,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    /// Take a token from the key's bucket if there is one
+    fn check(&self, key: &str) -> Decision {
+        self.decide(key, true)
+    }
+
+    /// Whether the key's bucket has a token, without taking it
+    fn peek(&self, key: &str) -> Decision {
+        self.decide(key, false)
+    }
+
+    fn decide(&self, key: &str, take: bool) -> Decision {
+        let now = Instant::now();
+        let burst = f64::from(self.burst);
+        let mut buckets = self.buckets.lock().expect("rate limit lock poisoned");
+
+        if buckets.len() >= self.max_keys && !buckets.contains_key(key) {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed && take {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: self.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((burst - bucket.tokens) / self.rate).ceil() as u64,
+            retry_after: ((1.0 - bucket.tokens).max(0.0) / self.rate).ceil() as u64,
+        }
+    }
+
+    /// Drop buckets that have refilled, since they are the same as a new bucket.
+    /// If every key is active the least recently used are dropped instead.
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < f64::from(self.burst)
+        });
+
+        if buckets.len() >= self.max_keys {
+            let keep = self.max_keys * 9 / 10;
+            let mut updated: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+            updated.sort_unstable();
+            let cutoff = updated[updated.len() - keep - 1];
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+
+        debug!(keys = buckets.len(), "evicted rate limit buckets");
+    }
+}
+
+/// Limiters keyed on the subject, which is only known once a later filter has
+/// authenticated the request, so they are checked by `Context::finish`.
+#[derive(Clone, Default)]
+struct SubjectLimits(Vec<Arc<Limiter>>);
+
+fn ip_key(req: &Request<Body>, config: &Config) -> String {
+    client_ip(req, &config.server.trusted_proxies)
+        .map_or_else(|| "unknown".to_owned(), |ip| ip.to_string())
+}
+
+/// Check the subject limiters recorded on the request, returning the most
+/// restrictive decision. Tokens are only taken once every limiter allows the
+/// request, so a rejected request doesn't use up the other limits.
+pub(super) fn check_subject_limits(req: &Request<Body>, config: &Config) -> Option<Decision> {
+    let limits = req.extensions().get::<SubjectLimits>()?;
+
+    let key = match req.extensions().get::<Claims>() {
+        Some(claims) => format!("sub:{}", claims.subject),
+        None => format!("ip:{}", ip_key(req, config)),
+    };
+
+    let denied = limits
+        .0
+        .iter()
+        .map(|limiter| limiter.peek(&key))
+        .find(|decision| !decision.allowed);
+    if denied.is_some() {
+        return denied;
+    }
+
+    limits
+        .0
+        .iter()
+        .map(|limiter| limiter.check(&key))
+        .min_by_key(|decision| (decision.allowed, decision.remaining))
+}
+
+pub(super) fn add_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert("RateLimit-Limit", decision.limit.into());
+    headers.insert("RateLimit-Remaining", decision.remaining.into());
+    headers.insert("RateLimit-Reset", decision.reset.into());
+}
+
+pub(super) fn too_many_requests(decision: &Decision) -> Result<Response<Body>> {
+    debug!(retry_after = decision.retry_after, "rate limit exceeded");
+
+    let mut resp = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, HeaderValue::from(decision.retry_after))
+        .body(Body::empty())?;
+    add_headers(resp.headers_mut(), decision);
+
+    Ok(resp)
+}
+
+/// Token bucket rate limiting. Buckets are kept in memory, so each instance
+/// limits separately and the counts are reset when the config is reloaded.
+pub struct RateLimitFilter {
+    key: RateLimitKey,
+    limiter: Arc<Limiter>,
+    matcher: Option<PathMatch>,
+}
+
+impl RateLimitFilter {
+    pub fn new(config: &RateLimitFilterConf) -> Result<Self> {
+        if config.rate <= 0.0 || config.burst == 0 {
+            anyhow::bail!("rate_limit rate and burst must be greater than zero");
+        }
+
+        let matcher = if config.paths.is_empty() {
+            None
+        } else {
+            Some(PathMatch::new(&config.paths, &config.not_paths)?)
+        };
+
+        Ok(RateLimitFilter {
+            key: config.key,
+            limiter: Arc::new(Limiter {
+                rate: config.rate,
+                burst: config.burst,
+                max_keys: config.max_keys.max(1),
+                buckets: Mutex::new(HashMap::new()),
+            }),
+            matcher,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Filter for RateLimitFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, mut req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        if let Some(matcher) = &self.matcher {
+            if !matcher.matches(req.uri().path())? {
+                return ctx.next(req).await;
+            }
+        }
+
+        let key = match self.key {
+            RateLimitKey::Subject => {
+                let mut limits = req
+                    .extensions()
+                    .get::<SubjectLimits>()
+                    .cloned()
+                    .unwrap_or_default();
+                limits.0.push(self.limiter.clone());
+                req.extensions_mut().insert(limits);
+
+                return ctx.next(req).await;
+            }
+            RateLimitKey::Ip => ip_key(&req, &ctx.state.config),
+            RateLimitKey::Route => req.uri().path().to_owned(),
+        };
+
+        let decision = self.limiter.check(&key);
+        if !decision.allowed {
+            return too_many_requests(&decision);
+        }
+
+        let mut resp = ctx.next(req).await?;
+        add_headers(resp.headers_mut(), &decision);
+
+        Ok(resp)
+    }
+}
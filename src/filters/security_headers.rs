@@ -0,0 +1,129 @@
+use crate::config::{HeaderMode, SecurityHeaders, SecurityHeadersFilterConf};
+use crate::filters::{Context, Filter};
+use crate::forwarded::request_scheme;
+use crate::path_match::PathMatch;
+use anyhow::Result;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, Request, Response};
+use tracing::trace;
+
+const DEFAULT_HSTS: &str = "max-age=31536000";
+
+fn merge(base: &SecurityHeaders, over: &SecurityHeaders) -> SecurityHeaders {
+    SecurityHeaders {
+        mode: over.mode.or(base.mode),
+        strict_transport_security: over
+            .strict_transport_security
+            .clone()
+            .or_else(|| base.strict_transport_security.clone()),
+        content_security_policy: over
+            .content_security_policy
+            .clone()
+            .or_else(|| base.content_security_policy.clone()),
+        x_frame_options: over
+            .x_frame_options
+            .clone()
+            .or_else(|| base.x_frame_options.clone()),
+        referrer_policy: over
+            .referrer_policy
+            .clone()
+            .or_else(|| base.referrer_policy.clone()),
+        permissions_policy: over
+            .permissions_policy
+            .clone()
+            .or_else(|| base.permissions_policy.clone()),
+    }
+}
+
+struct Route {
+    matcher: PathMatch,
+    headers: SecurityHeaders,
+}
+
+/// Adds security headers to every response. Routes override the default headers
+/// for matching paths, the first matching route is used.
+pub struct SecurityHeadersFilter {
+    headers: SecurityHeaders,
+    routes: Vec<Route>,
+}
+
+impl SecurityHeadersFilter {
+    pub fn new(config: &SecurityHeadersFilterConf) -> Result<Self> {
+        let mut routes = vec![];
+        for route in &config.routes {
+            routes.push(Route {
+                matcher: PathMatch::new(&route.paths, &route.not_paths)?,
+                headers: merge(&config.headers, &route.headers),
+            });
+        }
+
+        Ok(SecurityHeadersFilter {
+            headers: config.headers.clone(),
+            routes,
+        })
+    }
+
+    fn headers_for(&self, path: &str) -> Result<&SecurityHeaders> {
+        for route in &self.routes {
+            if route.matcher.matches(path)? {
+                return Ok(&route.headers);
+            }
+        }
+
+        Ok(&self.headers)
+    }
+}
+
+fn apply_headers(resp: &mut Response<Body>, headers: &SecurityHeaders, https: bool) -> Result<()> {
+    let mode = headers.mode.unwrap_or(HeaderMode::SetIfAbsent);
+
+    // HSTS is only meaningful over HTTPS, where it is on by default
+    let hsts = if https {
+        Some(headers.strict_transport_security.as_deref().unwrap_or(DEFAULT_HSTS))
+    } else {
+        None
+    };
+
+    let values: [(HeaderName, Option<&str>); 5] = [
+        (header::STRICT_TRANSPORT_SECURITY, hsts),
+        (
+            header::CONTENT_SECURITY_POLICY,
+            headers.content_security_policy.as_deref(),
+        ),
+        (header::X_FRAME_OPTIONS, headers.x_frame_options.as_deref()),
+        (header::REFERRER_POLICY, headers.referrer_policy.as_deref()),
+        (
+            HeaderName::from_static("permissions-policy"),
+            headers.permissions_policy.as_deref(),
+        ),
+    ];
+
+    for (name, value) in values {
+        let value = match value {
+            Some(value) if !value.is_empty() => value,
+            _ => continue,
+        };
+
+        if mode == HeaderMode::Override || !resp.headers().contains_key(&name) {
+            trace!(header = %name, "setting security header");
+            resp.headers_mut().insert(name, HeaderValue::from_str(value)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl Filter for SecurityHeadersFilter {
+    #[tracing::instrument(skip(self, req, ctx))]
+    async fn apply(&self, req: Request<Body>, ctx: Context<'_>) -> Result<Response<Body>> {
+        let headers = self.headers_for(req.uri().path())?;
+        // including HTTPS that a trusted proxy terminated
+        let https = request_scheme(&req, &ctx.state.config.server.trusted_proxies) == "https";
+
+        let mut resp = ctx.next(req).await?;
+        apply_headers(&mut resp, headers, https)?;
+
+        Ok(resp)
+    }
+}
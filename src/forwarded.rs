@@ -0,0 +1,233 @@
+use crate::config::Config;
+use crate::conn::{ConnInfo, Peer};
+use anyhow::Result;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Request};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove hop-by-hop headers, including any named by `Connection`. For upgrade
+/// requests `Upgrade` is kept so the target can switch protocols.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    for name in HOP_BY_HOP.iter().copied().chain(listed.iter().map(String::as_str)) {
+        if upgrade && name == "upgrade" {
+            continue;
+        }
+        headers.remove(name);
+    }
+
+    if upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+fn is_trusted(addr: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&addr))
+}
+
+/// Unix socket peers are only trusted when their listener says so, whatever
+/// `trusted_proxies` contains
+fn peer_trusted(peer: Peer, trusted_proxies: &[IpNet]) -> bool {
+    match peer {
+        Peer::Tcp(addr) => is_trusted(addr.ip(), trusted_proxies),
+        Peer::Unix { trusted } => trusted,
+    }
+}
+
+/// Entries in `X-Forwarded-For`, nearest hop first. Entries that can't be
+/// parsed are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut addrs: Vec<Option<IpAddr>> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|val| val.to_str().unwrap_or("unknown").split(','))
+        .map(|addr| {
+            let addr = addr.trim();
+            addr.parse::<IpAddr>()
+                .or_else(|_| addr.parse::<SocketAddr>().map(|sock| sock.ip()))
+                .ok()
+        })
+        .collect();
+    addrs.reverse();
+    addrs
+}
+
+/// The address of the client that made the request. When the peer is a trusted
+/// proxy, `X-Forwarded-For` is read from the nearest hop outwards and the client
+/// is the first address that isn't trusted. An entry that can't be parsed was
+/// written by the client (or an untrusted proxy) so the client is unknown. If
+/// every entry is trusted the furthest one is the client.
+pub fn client_ip(req: &Request<Body>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer = req.extensions().get::<ConnInfo>()?.peer;
+
+    if !peer_trusted(peer, trusted_proxies) {
+        return peer.ip();
+    }
+
+    let mut client = peer.ip();
+    for addr in forwarded_for(req.headers()) {
+        let addr = addr?;
+        client = Some(addr);
+        if !is_trusted(addr, trusted_proxies) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// The last value of a comma separated header, as added by the nearest proxy
+fn last_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .map(str::trim)
+        .next_back()
+}
+
+/// The scheme the client used. When the peer is a trusted proxy (eg. one that
+/// terminates TLS) it is taken from `X-Forwarded-Proto` or `Forwarded`.
+pub fn request_scheme(req: &Request<Body>, trusted_proxies: &[IpNet]) -> &'static str {
+    let conn = match req.extensions().get::<ConnInfo>() {
+        Some(conn) => conn,
+        None => return "http",
+    };
+    let own = if conn.tls { "https" } else { "http" };
+
+    if !peer_trusted(conn.peer, trusted_proxies) {
+        return own;
+    }
+
+    let forwarded = last_value(req.headers(), X_FORWARDED_PROTO).or_else(|| {
+        last_value(req.headers(), header::FORWARDED.as_str())?
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| name.eq_ignore_ascii_case("proto"))
+            .map(|(_, proto)| proto.trim_matches('"'))
+    });
+
+    match forwarded {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+        Some(proto) if proto.eq_ignore_ascii_case("http") => "http",
+        _ => own,
+    }
+}
+
+fn joined(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .collect();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+fn append(headers: &mut HeaderMap, name: &'static str, value: String) -> Result<()> {
+    let value = match joined(headers, name) {
+        Some(previous) => format!("{}, {}", previous, value),
+        None => value,
+    };
+
+    headers.insert(name, HeaderValue::from_str(&value)?);
+    Ok(())
+}
+
+fn forwarded_node(addr: Option<IpAddr>) -> String {
+    match addr {
+        Some(IpAddr::V4(addr)) => addr.to_string(),
+        Some(IpAddr::V6(addr)) => format!("\"[{}]\"", addr),
+        None => "unknown".to_owned(),
+    }
+}
+
+/// A `Forwarded` parameter value as a quoted-string
+fn quoted_string(val: &str) -> String {
+    format!("\"{}\"", val.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Tell the target about the original request with `X-Forwarded-*` and/or
+/// `Forwarded` headers. Values sent by the client are only kept when it is a
+/// trusted proxy, otherwise they are replaced.
+pub fn add_forwarded_headers(req: &mut Request<Body>, config: &Config) -> Result<()> {
+    let (peer, tls) = match req.extensions().get::<ConnInfo>() {
+        Some(conn) => (conn.peer, conn.tls),
+        None => return Ok(()),
+    };
+
+    let target = &config.target;
+    let proto = if tls { "https" } else { "http" };
+
+    let host = match req.headers().get(header::HOST) {
+        Some(host) => Some(host.to_str()?.to_owned()),
+        None => req.uri().authority().map(|auth| auth.to_string()),
+    };
+
+    let headers = req.headers_mut();
+
+    if !peer_trusted(peer, &config.server.trusted_proxies) {
+        for name in &[X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST] {
+            headers.remove(*name);
+        }
+        headers.remove(header::FORWARDED);
+    }
+
+    if target.forwarded_headers {
+        // a Unix socket peer has no address to add
+        if let Some(peer) = peer.ip() {
+            append(headers, X_FORWARDED_FOR, peer.to_string())?;
+        }
+
+        if !headers.contains_key(X_FORWARDED_PROTO) {
+            headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+        }
+
+        if let Some(host) = &host {
+            if !headers.contains_key(X_FORWARDED_HOST) {
+                headers.insert(X_FORWARDED_HOST, HeaderValue::from_str(host)?);
+            }
+        }
+    }
+
+    if target.forwarded {
+        let mut element = format!("for={};proto={}", forwarded_node(peer.ip()), proto);
+        if let Some(host) = &host {
+            element.push_str(&format!(";host={}", quoted_string(host)));
+        }
+
+        append(headers, "forwarded", element)?;
+    }
+
+    Ok(())
+}
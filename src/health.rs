@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::config::{Config, FilterConf, HealthConf, UserBaseConf};
+use crate::shutdown;
+use crate::state::State;
+use crate::userbase::get_user_base;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Probe {
+    Live,
+    Ready,
+}
+
+fn probe(req: &Request<Body>, path_prefix: &str) -> Option<Probe> {
+    let rest = req
+        .uri()
+        .path()
+        .strip_prefix(path_prefix.trim_end_matches('/'))?;
+
+    match rest {
+        "/live" => Some(Probe::Live),
+        "/ready" => Some(Probe::Ready),
+        _ => None,
+    }
+}
+
+/// The probe a request is for, wherever the health endpoints are served
+pub fn request_probe<'a>(req: &Request<Body>, state: &'a State) -> Option<(Probe, &'a HealthConf)> {
+    let health = state.config.server.health.as_ref()?;
+    Some((probe(req, &health.path_prefix)?, health))
+}
+
+/// The probe a request on a proxy listener is for, if the health endpoints
+/// are served on the listeners rather than on their own address
+pub fn listener_probe<'a>(
+    req: &Request<Body>,
+    state: &'a State,
+) -> Option<(Probe, &'a HealthConf)> {
+    request_probe(req, state).filter(|(_, health)| health.bind.is_none())
+}
+
+fn json_response(
+    status: StatusCode,
+    body: serde_json::Value,
+) -> hyper::http::Result<Response<Body>> {
+    Response::builder()
+        .status(status)
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        )
+        .header(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))
+        .body(Body::from(body.to_string()))
+}
+
+async fn with_timeout(timeout: Duration, check: impl Future<Output = Result<()>>) -> Result<()> {
+    tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")))
+}
+
+/// Any HTTP response counts, the target's own health is its business
+async fn check_target(state: &State) -> Result<()> {
+    let uri: Uri = state.config.target.url.as_str().parse()?;
+    let req = Request::get(uri).body(Body::empty())?;
+    state.client.request(req).await?;
+    Ok(())
+}
+
+fn user_bases(config: &Config) -> Vec<&UserBaseConf> {
+    let listener_filters = config
+        .server
+        .listeners
+        .iter()
+        .filter_map(|l| l.filters.as_ref());
+
+    config
+        .filters
+        .iter()
+        .chain(listener_filters.flatten())
+        .filter_map(|filter| match filter {
+            FilterConf::Basic(conf) => Some(&conf.user_base),
+            FilterConf::FormLogin(conf) => Some(&conf.user_base),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn check_user_bases(config: &Config) -> Result<()> {
+    for conf in user_bases(config) {
+        get_user_base(conf)?.check().await?;
+    }
+    Ok(())
+}
+
+type Checks = BTreeMap<&'static str, &'static str>;
+
+/// The last results of the checks that reach out to the target and user bases
+static UPSTREAM_CHECKS: Lazy<Mutex<Option<(Instant, Checks)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Only the status of each check is returned, the details are logged since the
+/// endpoints aren't authenticated
+fn record(checks: &mut Checks, name: &'static str, res: Result<()>) {
+    let status = match res {
+        Ok(()) => "ok",
+        Err(err) => {
+            warn!(check = name, "readiness check failed: {:#}", err);
+            "error"
+        }
+    };
+    checks.insert(name, status);
+}
+
+async fn upstream_checks(state: &State, health: &HealthConf) -> Checks {
+    // held while checking, so concurrent probes wait for one set of checks
+    let mut cached = UPSTREAM_CHECKS.lock().await;
+    if let Some((checked, checks)) = cached.as_ref() {
+        if checked.elapsed() < Duration::from_secs(health.cache) {
+            return checks.clone();
+        }
+    }
+
+    let mut checks = Checks::new();
+    let timeout = Duration::from_secs(health.timeout);
+
+    if health.check_target {
+        let res = with_timeout(timeout, check_target(state)).await;
+        record(&mut checks, "target", res);
+    }
+
+    if health.check_user_base {
+        let res = with_timeout(timeout, check_user_bases(&state.config)).await;
+        record(&mut checks, "user_base", res);
+    }
+
+    *cached = Some((Instant::now(), checks.clone()));
+    checks
+}
+
+async fn readiness(state: &State, health: &HealthConf) -> (bool, Checks) {
+    let mut checks = Checks::new();
+
+    // a config that fails to load is never swapped in, so there is always one
+    record(&mut checks, "config", Ok(()));
+
+    let draining = if shutdown::is_draining() {
+        Err(anyhow::anyhow!("draining"))
+    } else {
+        Ok(())
+    };
+    record(&mut checks, "shutdown", draining);
+
+    if health.check_target || health.check_user_base {
+        checks.extend(upstream_checks(state, health).await);
+    }
+
+    let ready = checks.values().all(|status| *status == "ok");
+    (ready, checks)
+}
+
+pub async fn respond(
+    probe: Probe,
+    state: &State,
+    health: &HealthConf,
+) -> hyper::http::Result<Response<Body>> {
+    debug!(?probe, "health check");
+
+    match probe {
+        Probe::Live => json_response(StatusCode::OK, json!({ "status": "ok" })),
+        Probe::Ready => {
+            let (ready, checks) = readiness(state, health).await;
+            let (status, body) = if ready {
+                (StatusCode::OK, json!({ "status": "ok", "checks": checks }))
+            } else {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    json!({ "status": "unavailable", "checks": checks }),
+                )
+            };
+            json_response(status, body)
+        }
+    }
+}
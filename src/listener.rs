@@ -0,0 +1,389 @@
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use hyper::header;
+use hyper::http::uri::Authority;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use rustls::Session;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tracing::{info, warn};
+
+use crate::config::{Listener, TlsConfig};
+use crate::conn::{ConnInfo, Peer};
+use crate::proxy_protocol;
+use crate::shutdown;
+use crate::state::STATE;
+use crate::tls::get_server_tls_config;
+
+/// How long a client has to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An accepted TCP connection. When the listener uses the PROXY protocol the
+/// remote address is the client address from the header.
+struct TcpConn {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl TcpConn {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl AsyncRead for TcpConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+async fn read_proxy_header(mut stream: TcpStream, peer: SocketAddr) -> Option<TcpConn> {
+    let header = tokio::time::timeout(
+        PROXY_HEADER_TIMEOUT,
+        proxy_protocol::read_header(&mut stream),
+    );
+
+    match header.await {
+        Ok(Ok(client)) => Some(TcpConn {
+            stream,
+            remote_addr: client.unwrap_or(peer),
+        }),
+        Ok(Err(err)) => {
+            warn!(%peer, "error reading PROXY protocol header: {}", err);
+            None
+        }
+        Err(_) => {
+            warn!(%peer, "timed out reading PROXY protocol header");
+            None
+        }
+    }
+}
+
+async fn accept_loop(listener: TcpListener, tx: mpsc::Sender<TcpConn>, proxy_protocol: bool) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tx.closed() => break,
+            _ = shutdown::stopping() => break,
+        };
+
+        match accepted {
+            Ok((stream, peer)) if proxy_protocol => {
+                // read the header in its own task so a slow client can't block others
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Some(conn) = read_proxy_header(stream, peer).await {
+                        let _ = tx.send(conn).await;
+                    }
+                });
+            }
+            Ok((stream, peer)) => {
+                let conn = TcpConn {
+                    stream,
+                    remote_addr: peer,
+                };
+                let _ = tx.send(conn).await;
+            }
+            Err(err) => {
+                // eg. too many open files, so back off rather than spinning
+                warn!("error accepting connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Connections accepted on a TCP listener, once any PROXY protocol header has been read
+struct TcpIncoming {
+    rx: mpsc::Receiver<TcpConn>,
+}
+
+impl TcpIncoming {
+    async fn bind(addr: SocketAddr, proxy_protocol: bool) -> Result<TcpIncoming> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("error binding {}", addr))?;
+
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(accept_loop(listener, tx, proxy_protocol));
+
+        Ok(TcpIncoming { rx })
+    }
+
+    fn poll_next(&mut self, cx: &mut TaskContext<'_>) -> Poll<Option<io::Result<TcpConn>>> {
+        self.rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+impl tls_listener::AsyncAccept for TcpIncoming {
+    type Connection = TcpConn;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<TcpConn, io::Error>> {
+        self.get_mut().poll_next(cx).map(|conn| {
+            conn.unwrap_or_else(|| Err(io::Error::other("listener closed")))
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Proxy,
+    /// redirect to HTTPS on the given port
+    RedirectHttps(Option<u16>),
+    /// only answer health checks and/or metrics scrapes
+    Internal { health: bool, metrics: bool },
+}
+
+fn redirect_https(req: &Request<Body>, port: Option<u16>) -> hyper::http::Result<Response<Body>> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+
+    let host = match host {
+        Some(authority) => authority.host().to_owned(),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+        }
+    };
+
+    let authority = match port {
+        None | Some(443) => host,
+        Some(port) => format!("{}:{}", host, port),
+    };
+    let path = req.uri().path_and_query().map_or("/", |pnq| pnq.as_str());
+
+    // 308 rather than 301 so that clients repeat the method and body
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, format!("https://{}{}", authority, path))
+        .body(Body::empty())
+}
+
+/// Handle a request on an internal listener, which has nothing else on it
+async fn internal(
+    req: Request<Body>,
+    health: bool,
+    metrics: bool,
+) -> hyper::http::Result<Response<Body>> {
+    let state = STATE.load_full().expect("state unset?");
+
+    // the sections may have been removed by a reload
+    if let Some((probe, conf)) = crate::health::request_probe(&req, &state).filter(|_| health) {
+        return crate::health::respond(probe, &state, conf).await;
+    }
+    if metrics && crate::metrics::is_request(&req, &state) {
+        return crate::metrics::respond();
+    }
+
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+}
+
+async fn dispatch(
+    req: Request<Body>,
+    conn: ConnInfo,
+    mode: Mode,
+) -> hyper::http::Result<Response<Body>> {
+    match mode {
+        Mode::Proxy => crate::handle(req, conn).await,
+        Mode::RedirectHttps(port) => redirect_https(&req, port),
+        Mode::Internal { health, metrics } => internal(req, health, metrics).await,
+    }
+}
+
+async fn serve_http(mut incoming: TcpIncoming, name: Arc<str>, mode: Mode) -> Result<()> {
+    let mk_service = make_service_fn(move |conn: &TcpConn| {
+        let conn = ConnInfo {
+            peer: Peer::Tcp(conn.remote_addr()),
+            tls: false,
+            client_cert: None,
+            listener: name.clone(),
+        };
+        async move { Ok::<_, Infallible>(service_fn(move |req| dispatch(req, conn.clone(), mode))) }
+    });
+
+    let incoming = accept::poll_fn(move |cx| incoming.poll_next(cx));
+    hyper::Server::builder(incoming)
+        .serve(mk_service)
+        .with_graceful_shutdown(shutdown::stopping())
+        .await?;
+
+    Ok(())
+}
+
+async fn serve_https(
+    incoming: TcpIncoming,
+    name: Arc<str>,
+    mode: Mode,
+    tls_config: &TlsConfig,
+) -> Result<()> {
+    let server_config = get_server_tls_config(tls_config)?;
+
+    let tls = tls_listener::builder(server_config)
+        .listen(incoming)
+        .filter(|conn| {
+            if let Err(err) = conn {
+                warn!("error accepting HTTPS connection: {}", err);
+                std::future::ready(false)
+            } else {
+                std::future::ready(true)
+            }
+        });
+
+    let mk_service = make_service_fn(move |conn: &TlsStream<TcpConn>| {
+        let (stream, session) = conn.get_ref();
+        let conn = ConnInfo {
+            peer: Peer::Tcp(stream.remote_addr()),
+            tls: true,
+            client_cert: session
+                .get_peer_certificates()
+                .and_then(|certs| certs.into_iter().next())
+                .map(Arc::new),
+            listener: name.clone(),
+        };
+        async move { Ok::<_, Infallible>(service_fn(move |req| dispatch(req, conn.clone(), mode))) }
+    });
+
+    hyper::Server::builder(accept::from_stream(tls))
+        .serve(mk_service)
+        .with_graceful_shutdown(shutdown::stopping())
+        .await?;
+
+    Ok(())
+}
+
+async fn serve_unix(path: &str, trusted: bool, mode: Mode) -> Result<()> {
+    // a socket left behind by a previous run would make the bind fail
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("error removing old unix socket: {}", path))?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("error binding unix socket: {}", path))?;
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(stream, _addr)| stream)))
+    });
+
+    let name: Arc<str> = path.into();
+    let mk_service = make_service_fn(move |_conn: &UnixStream| {
+        let conn = ConnInfo {
+            peer: Peer::Unix { trusted },
+            tls: false,
+            client_cert: None,
+            listener: name.clone(),
+        };
+        async move { Ok::<_, Infallible>(service_fn(move |req| dispatch(req, conn.clone(), mode))) }
+    });
+
+    info!("server listening for HTTP on unix socket {}", path);
+    hyper::Server::builder(incoming)
+        .serve(mk_service)
+        .with_graceful_shutdown(shutdown::stopping())
+        .await?;
+
+    Ok(())
+}
+
+/// Serve requests on a listener until the server stops
+pub async fn serve(listener: &Listener, tls_config: Option<&TlsConfig>) -> Result<()> {
+    let mode = if listener.redirect_https {
+        Mode::RedirectHttps(listener.https_port)
+    } else {
+        Mode::Proxy
+    };
+
+    match (&listener.bind, &listener.unix) {
+        (Some(bind), _) => {
+            let addr = bind
+                .parse()
+                .with_context(|| format!("invalid listener address: {}", bind))?;
+            let proxy_protocol = listener.proxy_protocol;
+            let incoming = TcpIncoming::bind(addr, proxy_protocol).await?;
+
+            match tls_config.filter(|_| listener.tls) {
+                Some(tls_config) => {
+                    info!(proxy_protocol, "server listening for HTTPS on {:?}", addr);
+                    serve_https(incoming, bind.as_str().into(), mode, tls_config).await
+                }
+                None => {
+                    info!(proxy_protocol, "server listening for HTTP on {:?}", addr);
+                    serve_http(incoming, bind.as_str().into(), mode).await
+                }
+            }
+        }
+        (None, Some(path)) => serve_unix(path, listener.trusted_proxy, mode).await,
+        (None, None) => anyhow::bail!("listener has no bind address or unix socket"),
+    }
+}
+
+/// Serve the health endpoints and/or metrics on their own address
+pub async fn serve_internal(bind: &str, health: bool, metrics: bool) -> Result<()> {
+    let addr = bind
+        .parse()
+        .with_context(|| format!("invalid internal listener address: {}", bind))?;
+    let incoming = TcpIncoming::bind(addr, false).await?;
+
+    info!(health, metrics, "internal endpoints listening on {:?}", addr);
+    serve_http(incoming, bind.into(), Mode::Internal { health, metrics }).await
+}
@@ -1,7 +1,10 @@
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use chrono::SecondsFormat;
 use colored::Colorize;
 use std::fmt::{Debug, Result as FmtResult, Write};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::sync::Arc;
 use tracing::field::{Field, Visit};
 use tracing::{Event, Level, Subscriber};
 use tracing_log::NormalizeEvent;
@@ -11,6 +14,8 @@ use tracing_subscriber::prelude::*;
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::{fmt, EnvFilter};
 
+use crate::telemetry;
+
 fn level_color(level: Level, msg: String) -> impl std::fmt::Display {
     match level {
         Level::ERROR => msg.bright_red(),
@@ -62,7 +67,7 @@ where
             meta.target(),
         );
 
-        write!(writer, "{}\n", level_color(*meta.level(), header))?;
+        writeln!(writer, "{}", level_color(*meta.level(), header))?;
 
         ctx.format_fields(writer, event)?;
 
@@ -92,25 +97,90 @@ impl<'w> FormatFields<'w> for SemiCompact {
     }
 }
 
+/// Where application logs are written, from `SEALPROXY_LOG_FILE` or stdout
+#[derive(Clone)]
+enum LogWriter {
+    Stdout,
+    File(Arc<File>),
+}
+
+impl LogWriter {
+    fn from_env() -> Result<LogWriter> {
+        match std::env::var_os("SEALPROXY_LOG_FILE") {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("error opening log file: {:?}", path))?;
+                Ok(LogWriter::File(Arc::new(file)))
+            }
+            None => Ok(LogWriter::Stdout),
+        }
+    }
+}
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::Stdout => io::stdout().write(buf),
+            LogWriter::File(file) => (&**file).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Stdout => io::stdout().flush(),
+            LogWriter::File(file) => (&**file).flush(),
+        }
+    }
+}
+
+/// Logs are `pretty` (the default, multi-line and coloured), `compact` (one
+/// line per event) or `json` (one object per line), set by `SEALPROXY_LOG_FORMAT`
 pub fn setup() -> Result<()> {
-    let use_json = false; // TODO
+    let format = std::env::var("SEALPROXY_LOG_FORMAT").unwrap_or_else(|_| "pretty".to_owned());
+    let writer = LogWriter::from_env()?;
+    let ansi = matches!(writer, LogWriter::Stdout);
+    let make_writer = move || writer.clone();
 
     let filter_layer = EnvFilter::from_env("SEALPROXY_LOG");
 
-    if use_json {
-        tracing_subscriber::registry()
-            .with(filter_layer)
-            .with(fmt::layer().json())
-            .init();
-    } else {
-        let fmt_layer = fmt::layer()
-            .event_format(SemiCompact)
-            .fmt_fields(SemiCompact);
-
-        tracing_subscriber::registry()
-            .with(filter_layer)
-            .with(fmt_layer)
-            .init();
+    // spans are only exported for the levels SEALPROXY_LOG enables
+    let telemetry_layer =
+        telemetry::tracer()?.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    let registry = tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(telemetry_layer);
+
+    match format.as_str() {
+        "pretty" => {
+            if !ansi {
+                colored::control::set_override(false);
+            }
+
+            let fmt_layer = fmt::layer()
+                .event_format(SemiCompact)
+                .fmt_fields(SemiCompact)
+                .with_writer(make_writer);
+            registry.with(fmt_layer).init();
+        }
+        "compact" => {
+            let fmt_layer = fmt::layer()
+                .compact()
+                .with_ansi(ansi)
+                .with_writer(make_writer);
+            registry.with(fmt_layer).init();
+        }
+        "json" => {
+            let fmt_layer = fmt::layer().json().with_writer(make_writer);
+            registry.with(fmt_layer).init();
+        }
+        _ => anyhow::bail!(
+            "unknown SEALPROXY_LOG_FORMAT (pretty, compact or json): {}",
+            format
+        ),
     }
 
     Ok(())
@@ -1,45 +1,102 @@
-use std::convert::Infallible;
-
 use anyhow::Result;
-use futures_util::StreamExt;
-use hyper::server::accept;
-use hyper::server::conn::AddrIncoming;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, StatusCode};
+use futures_util::FutureExt;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode, Version};
+use std::time::{Duration, Instant};
 use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::conn::ConnInfo;
 use crate::state::STATE;
-use crate::tls::get_server_tls_config;
 
+mod access_log;
 mod config;
+pub mod conn;
 pub mod filters;
+pub mod forwarded;
+mod health;
+mod listener;
 mod logging;
+mod metrics;
+mod proxy_protocol;
+mod shutdown;
 pub mod path_match;
+pub mod safe_redirect;
 pub mod session;
 mod state;
 pub mod target;
+mod telemetry;
 mod tls;
 pub mod userbase;
 mod upgrade;
 
 #[tracing::instrument(
-    skip(req),
+    skip(req, conn),
     fields(
+        peer = % conn.peer,
         url = % req.uri(),
         method = % req.method(),
-        request_id = % Uuid::new_v4().to_string(),
+        request_id = tracing::field::Empty,
+        otel.name = % format!("HTTP {}", req.method()),
+        otel.kind = "server",
+        http.status_code = tracing::field::Empty,
     )
 )]
-async fn handle(req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+async fn handle(mut req: Request<Body>, conn: ConnInfo) -> hyper::http::Result<Response<Body>> {
     let state = STATE.load_full().expect("state unset?");
 
-    state.handle(req).await.or_else(|err| {
-        warn!(?err, "internal server error");
-        Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .body(Body::empty())
-    })
+    let request_id = Uuid::new_v4().to_string();
+    tracing::Span::current().record("request_id", &request_id.as_str());
+    telemetry::extract_context(&req);
+
+    req.extensions_mut().insert(conn);
+
+    // HTTP/2 requests carry the host in the URI rather than a Host header
+    if !req.headers().contains_key(header::HOST) {
+        let authority = req.uri().authority().map(|a| HeaderValue::from_str(a.as_str()));
+        if let Some(Ok(host)) = authority {
+            req.headers_mut().insert(header::HOST, host);
+        }
+    }
+
+    let version = req.version();
+    let start = Instant::now();
+    let route = state.metric_routes.name(req.uri().path()).to_owned();
+
+    let mut resp = match health::listener_probe(&req, &state) {
+        Some((probe, health)) => health::respond(probe, &state, health).await?,
+        None => {
+            // health checks are left out of the access log
+            let entry = state
+                .access_log
+                .as_ref()
+                .map(|_| access_log::Entry::new(&req, &state.config, &request_id));
+
+            let resp = state.handle(req).await.or_else(|err| {
+                warn!(?err, "internal server error");
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Body::empty())
+            })?;
+
+            if let (Some(access_log), Some(entry)) = (&state.access_log, entry) {
+                access_log.log(&entry, &resp);
+            }
+            resp
+        }
+    };
+
+    metrics::observe_request(&route, resp.status(), start.elapsed());
+    tracing::Span::current().record("http.status_code", &resp.status().as_u16());
+
+    // move keep-alive clients to other instances while shutting down
+    let keep_alive = version < Version::HTTP_2 && resp.status() != StatusCode::SWITCHING_PROTOCOLS;
+    if keep_alive && shutdown::is_draining() {
+        resp.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+
+    Ok(resp)
 }
 
 #[tokio::main]
@@ -59,45 +116,72 @@ async fn main() -> Result<()> {
 
     let args = app.get_matches();
 
+    metrics::init();
+
     let config_arg = args.value_of("config").expect("config is mandatory");
     let state = state::init(config_arg)?;
 
-    let bind = state
+    let tls_config = state.config.server.tls.as_ref();
+    let mut servers: Vec<_> = state
         .config
         .server
-        .bind
-        .as_deref()
-        .unwrap_or("0.0.0.0:8000");
-    let addr = bind.parse()?;
-
-    let incoming = AddrIncoming::bind(&addr)?;
-
-    if let Some(tls_config) = &state.config.server.tls {
-        let server_config = get_server_tls_config(tls_config)?;
-
-        let tls = tls_listener::builder(server_config)
-            .listen(incoming)
-            .filter(|conn| {
-                if let Err(err) = conn {
-                    warn!("error accepting HTTPS connection: {}", err);
-                    std::future::ready(false)
-                } else {
-                    std::future::ready(true)
-                }
-            });
-
-        let mk_service = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
-
-        info!("server listening for HTTPS on {:?}", addr);
-        hyper::Server::builder(accept::from_stream(tls))
-            .serve(mk_service)
-            .await?;
-    } else {
-        let mk_service = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
-
-        info!("server listening for HTTP on {:?}", addr);
-        hyper::Server::builder(incoming).serve(mk_service).await?;
+        .listeners
+        .iter()
+        .map(|listener| listener::serve(listener, tls_config).boxed())
+        .collect();
+
+    // health checks and metrics may share an address
+    let server = &state.config.server;
+    let health_bind = server.health.as_ref().and_then(|health| health.bind.as_deref());
+    let metrics_bind = server.metrics.as_ref().map(|metrics| metrics.bind.as_str());
+
+    let mut internal: Vec<&str> = health_bind.into_iter().chain(metrics_bind).collect();
+    internal.dedup();
+    for bind in internal {
+        let (health, metrics) = (health_bind == Some(bind), metrics_bind == Some(bind));
+        servers.push(listener::serve_internal(bind, health, metrics).boxed());
+    }
+
+    let servers = futures_util::future::try_join_all(servers);
+    tokio::pin!(servers);
+
+    tokio::select! {
+        res = &mut servers => {
+            res?;
+            return Ok(());
+        }
+        res = shutdown::signal_received() => res?,
     }
 
+    // the config may have been reloaded since startup
+    let server = &STATE.load_full().expect("state unset?").config.server;
+    let (delay, drain_timeout) = (server.shutdown_delay, server.drain_timeout);
+
+    shutdown::start_draining();
+    if delay > 0 {
+        info!(delay, "draining before closing listeners");
+        tokio::select! {
+            res = &mut servers => return res.map(drop),
+            _ = tokio::time::sleep(Duration::from_secs(delay)) => {}
+        }
+    }
+
+    info!(drain_timeout, "closing listeners and waiting for connections to finish");
+    shutdown::stop_accepting();
+
+    let drain = async {
+        servers.await?;
+        shutdown::tunnels_closed().await;
+        Ok::<_, anyhow::Error>(())
+    };
+
+    match tokio::time::timeout(Duration::from_secs(drain_timeout), drain).await {
+        Ok(res) => res?,
+        Err(_) => warn!("connections still open after the drain timeout"),
+    }
+
+    telemetry::shutdown().await;
+    info!("shutdown complete");
+
     Ok(())
 }
@@ -0,0 +1,207 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::header;
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+
+use crate::config::MetricsConf;
+use crate::path_match::PathMatch;
+use crate::state::State;
+use crate::tls::Certificates;
+use crate::userbase::{DynUserBase, LookupResult};
+
+struct Metrics {
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    filter_decisions: IntCounterVec,
+    logins: IntCounterVec,
+    upstream_duration: Histogram,
+    upstream_errors: IntCounterVec,
+    upgraded_connections: IntGauge,
+    config_reloads: IntCounterVec,
+    cert_expiry: IntGaugeVec,
+}
+
+static METRICS: Lazy<Metrics> =
+    Lazy::new(|| Metrics::register().expect("error registering metrics"));
+
+impl Metrics {
+    fn register() -> prometheus::Result<Metrics> {
+        Ok(Metrics {
+            requests: register_int_counter_vec!(
+                "sealproxy_http_requests_total",
+                "Requests handled, by route and status",
+                &["route", "status"]
+            )?,
+            request_duration: register_histogram_vec!(
+                "sealproxy_http_request_duration_seconds",
+                "Time to respond to requests, by route and status",
+                &["route", "status"]
+            )?,
+            filter_decisions: register_int_counter_vec!(
+                "sealproxy_filter_decisions_total",
+                "What each filter did with the requests it saw",
+                &["filter", "decision"]
+            )?,
+            logins: register_int_counter_vec!(
+                "sealproxy_logins_total",
+                "Login attempts, by mechanism, user base and result",
+                &["mechanism", "user_base", "result"]
+            )?,
+            upstream_duration: register_histogram!(
+                "sealproxy_upstream_duration_seconds",
+                "Time for the target to respond"
+            )?,
+            upstream_errors: register_int_counter_vec!(
+                "sealproxy_upstream_errors_total",
+                "Requests that could not be sent to the target",
+                &["kind"]
+            )?,
+            upgraded_connections: register_int_gauge!(
+                "sealproxy_upgraded_connections",
+                "Upgraded connections (eg. websockets) currently open"
+            )?,
+            config_reloads: register_int_counter_vec!(
+                "sealproxy_config_reloads_total",
+                "Configuration reloads, by result",
+                &["result"]
+            )?,
+            cert_expiry: register_int_gauge_vec!(
+                "sealproxy_certificate_expiry_timestamp_seconds",
+                "When each TLS certificate expires",
+                &["file"]
+            )?,
+        })
+    }
+}
+
+/// Register the metrics so that they are all reported from the first scrape
+pub fn init() {
+    for result in &["success", "failure"] {
+        METRICS.config_reloads.with_label_values(&[result]);
+    }
+    for kind in &["request", "upgrade"] {
+        METRICS.upstream_errors.with_label_values(&[kind]);
+    }
+}
+
+/// Names requests by the configured routes, to keep the number of label
+/// values small. Requests matching no route are named `other`.
+pub struct Routes {
+    routes: Vec<(String, PathMatch)>,
+}
+
+impl Routes {
+    pub fn from_config(config: Option<&MetricsConf>) -> Result<Routes> {
+        let routes = config
+            .map_or(&[][..], |config| config.routes.as_slice())
+            .iter()
+            .map(|route| {
+                Ok((
+                    route.name.clone(),
+                    PathMatch::new(&route.paths, &route.not_paths)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Routes { routes })
+    }
+
+    pub fn name(&self, path: &str) -> &str {
+        self.routes
+            .iter()
+            .find(|(_, matcher)| matcher.matches(path).unwrap_or(false))
+            .map_or("other", |(name, _)| name.as_str())
+    }
+}
+
+pub fn observe_request(route: &str, status: StatusCode, elapsed: Duration) {
+    let status = status.as_str();
+    METRICS.requests.with_label_values(&[route, status]).inc();
+    METRICS
+        .request_duration
+        .with_label_values(&[route, status])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn filter_decision(filter: &str, decision: &str) {
+    METRICS
+        .filter_decisions
+        .with_label_values(&[filter, decision])
+        .inc();
+}
+
+pub fn login(mechanism: &str, user_base: &str, result: &str) {
+    METRICS
+        .logins
+        .with_label_values(&[mechanism, user_base, result])
+        .inc();
+}
+
+/// Count a login checked against a user base
+pub fn user_base_login(mechanism: &str, user_base: &DynUserBase, result: &Result<LookupResult>) {
+    let result = match result {
+        Ok(LookupResult::Success { .. }) => "success",
+        Ok(LookupResult::NoSuchUser) | Ok(LookupResult::IncorrectPassword) => "failure",
+        Ok(LookupResult::Other(_)) | Err(_) => "error",
+    };
+    login(mechanism, user_base.kind(), result);
+}
+
+pub fn observe_upstream(elapsed: Duration) {
+    METRICS.upstream_duration.observe(elapsed.as_secs_f64());
+}
+
+/// `kind` is `request` or `upgrade`
+pub fn upstream_error(kind: &str) {
+    METRICS.upstream_errors.with_label_values(&[kind]).inc();
+}
+
+pub fn config_reload(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    METRICS.config_reloads.with_label_values(&[result]).inc();
+}
+
+/// Replace the expiry times with those of newly loaded certificates
+pub fn set_cert_expiry(certs: Option<&Certificates>) {
+    METRICS.cert_expiry.reset();
+    for (file, expires) in certs.iter().flat_map(|certs| certs.expiry()) {
+        METRICS.cert_expiry.with_label_values(&[file]).set(*expires);
+    }
+}
+
+/// True for a request to the metrics path
+pub fn is_request(req: &Request<Body>, state: &State) -> bool {
+    state
+        .config
+        .server
+        .metrics
+        .as_ref()
+        .is_some_and(|metrics| req.uri().path() == metrics.path)
+}
+
+/// The metrics in the Prometheus text format
+pub fn respond() -> hyper::http::Result<Response<Body>> {
+    METRICS
+        .upgraded_connections
+        .set(crate::shutdown::open_tunnels() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buf) {
+        tracing::warn!("error encoding metrics: {}", err);
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty());
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buf))
+}
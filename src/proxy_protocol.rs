@@ -0,0 +1,93 @@
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// v1 headers are at most 107 bytes including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Read a PROXY protocol v1 or v2 header from the start of a connection,
+/// returning the source address it gives. `None` is returned for headers that
+/// don't carry an address, such as health checks from the proxy itself.
+///
+/// Exactly the header is read, so the rest of the stream is left untouched.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // the shortest v1 header (`PROXY UNKNOWN\r\n`) is longer than the v2 signature
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(anyhow!("connection did not start with a PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, _dst, sport, _dport] => {
+            let ip: Ipv4Addr = src.parse()?;
+            Ok(Some(SocketAddr::new(ip.into(), sport.parse()?)))
+        }
+        ["PROXY", "TCP6", src, _dst, sport, _dport] => {
+            let ip: Ipv6Addr = src.parse()?;
+            Ok(Some(SocketAddr::new(ip.into(), sport.parse()?)))
+        }
+        _ => Err(anyhow!("invalid PROXY protocol v1 header: {:?}", line)),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+
+    // addresses are followed by optional TLVs, which are read and ignored
+    let mut data = vec![0; usize::from(len)];
+    stream.read_exact(&mut data).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL, eg. a health check from the proxy
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(anyhow!("unsupported PROXY protocol command: {}", command)),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+
+    match family {
+        // TCP over IPv4
+        0x11 if data.len() >= 12 => {
+            let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        }
+        // TCP over IPv6
+        0x21 if data.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&data[..16]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(octets.into()), port(32))))
+        }
+        0x11 | 0x21 => Err(anyhow!("PROXY protocol v2 address block is too short")),
+        // unspecified, or a family without an IP address
+        _ => Ok(None),
+    }
+}
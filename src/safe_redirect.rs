@@ -0,0 +1,58 @@
+use tracing::warn;
+use url::Url;
+
+/// Allowed hosts are exact names, or `*.example.com` to allow any subdomain
+fn host_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+
+    allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_prefix('*') {
+            Some(domain) => domain.starts_with('.') && host.ends_with(domain),
+            None => host == allowed,
+        }
+    })
+}
+
+/// Check that a redirect target supplied by the client stays on this site: either
+/// a path on the same origin, or an http(s) URL on one of the allowed hosts.
+pub fn is_safe_redirect(location: &str, allowed_hosts: &[String]) -> bool {
+    // browsers ignore tabs and newlines, so "/\t/evil.example" becomes "//evil.example"
+    if location.chars().any(|c| c.is_ascii_control()) {
+        return false;
+    }
+
+    if location.starts_with('/') {
+        // "//host" and "/\host" are treated by browsers as another host
+        return !location.starts_with("//") && !location.starts_with("/\\");
+    }
+
+    match Url::parse(location) {
+        Ok(url) => {
+            matches!(url.scheme(), "http" | "https")
+                && url
+                    .host_str()
+                    .is_some_and(|host| host_allowed(host, allowed_hosts))
+        }
+        Err(_) => false,
+    }
+}
+
+/// The first safe location out of the candidates, in order of preference,
+/// falling back to the site root.
+pub fn safe_redirect<'a>(
+    candidates: impl IntoIterator<Item = Option<&'a str>>,
+    allowed_hosts: &[String],
+) -> &'a str {
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|location| {
+            let safe = is_safe_redirect(location, allowed_hosts);
+            if !safe {
+                warn!(%location, "ignoring unsafe redirect");
+            }
+            safe
+        })
+        .unwrap_or("/")
+}
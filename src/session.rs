@@ -1,9 +1,15 @@
+mod encryption;
+mod jwk;
+mod keys;
+pub mod token;
+
+pub use keys::KeySet;
+
 use crate::state::State;
 use anyhow::Result;
 use cookie::{Cookie, SameSite};
 use hyper::header::{self, HeaderValue};
 use hyper::{Body, Response};
-use jsonwebtoken::{Algorithm, Header};
 use serde::{Deserialize, Serialize};
 use time::{Duration, OffsetDateTime};
 
@@ -14,15 +20,18 @@ pub const SESSION_COOKIE: &str = "seal.sid";
 pub struct Claims {
     pub issuer: String,
     pub subject: String,
+    pub groups: Vec<String>,
+    pub scopes: Vec<String>,
 }
 
-// TODO - don't expose this struct
 #[derive(Serialize, Deserialize)]
-pub struct JwtClaims {
-    pub aud: String,
-    pub iss: String,
-    pub sub: String,
-    pub exp: i64,
+struct JwtClaims {
+    aud: String,
+    iss: String,
+    sub: String,
+    exp: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
 }
 
 pub fn establish_session(
@@ -35,10 +44,10 @@ pub fn establish_session(
         iss: claims.issuer,
         sub: claims.subject,
         exp: (OffsetDateTime::now_utc() + Duration::days(1)).unix_timestamp(),
+        groups: claims.groups,
     };
 
-    let header = Header::new(Algorithm::RS256);
-    let jwt = jsonwebtoken::encode(&header, &jwt_claims, &state.session_key)?;
+    let jwt = token::seal(&state.session_keys, &jwt_claims)?;
 
     let cookie = Cookie::build(SESSION_COOKIE, jwt)
         .secure(false) // TODO - unsecure until HTTPS is enabled by default
@@ -51,3 +60,14 @@ pub fn establish_session(
 
     Ok(resp)
 }
+
+pub fn validate_session(jwt: &str, state: &State) -> Result<Claims> {
+    let jwt_claims: JwtClaims = token::unseal(&state.session_keys, jwt, AUDIENCE)?;
+
+    Ok(Claims {
+        issuer: jwt_claims.iss,
+        subject: jwt_claims.sub,
+        groups: jwt_claims.groups,
+        ..Default::default()
+    })
+}
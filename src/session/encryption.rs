@@ -0,0 +1,148 @@
+use crate::config::SessionEncryption;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+const ALG: &str = "dir";
+const ENC: &str = "A256GCM";
+
+#[derive(Serialize, Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+    kid: String,
+    cty: String,
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(data: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(data, base64::URL_SAFE_NO_PAD)?)
+}
+
+struct EncryptionKey {
+    kid: String,
+    key: LessSafeKey,
+}
+
+impl EncryptionKey {
+    /// Key files contain 32 random bytes, base64 encoded (eg. `openssl rand -base64 32`).
+    fn load(kid: &str, file: &str) -> Result<EncryptionKey> {
+        let data = std::fs::read_to_string(file)
+            .with_context(|| format!("error reading session encryption key file: {}", file))?;
+        let bytes = base64::decode(data.trim())
+            .with_context(|| format!("session encryption key is not valid base64: {}", file))?;
+
+        let key = UnboundKey::new(&AES_256_GCM, &bytes)
+            .map_err(|_| anyhow!("session encryption key must be 32 bytes: {}", file))?;
+
+        Ok(EncryptionKey {
+            kid: kid.to_owned(),
+            key: LessSafeKey::new(key),
+        })
+    }
+}
+
+/// Encrypts session tokens as compact JWEs using direct AES-256-GCM encryption,
+/// so the claims inside the signed token can't be read by the client.
+pub struct Encryption {
+    current: EncryptionKey,
+    retired: Vec<EncryptionKey>,
+    accept_unencrypted_until: Option<DateTime<Utc>>,
+    rng: SystemRandom,
+}
+
+impl Encryption {
+    pub fn from_config(config: &SessionEncryption) -> Result<Encryption> {
+        let current = EncryptionKey::load(&config.key_id, &config.key_file)?;
+
+        let mut retired = Vec::new();
+        for key in &config.retired_keys {
+            if key.key_id == current.kid {
+                anyhow::bail!(
+                    "retired session encryption key has the same key id as the current key: {}",
+                    current.kid
+                );
+            }
+            retired.push(EncryptionKey::load(&key.key_id, &key.key_file)?);
+        }
+
+        Ok(Encryption {
+            current,
+            retired,
+            accept_unencrypted_until: config.accept_unencrypted_until,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Whether tokens issued before encryption was turned on are still accepted
+    pub fn accepts_unencrypted(&self) -> bool {
+        self.accept_unencrypted_until
+            .is_some_and(|until| Utc::now() < until)
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let header = JweHeader {
+            alg: ALG.to_owned(),
+            enc: ENC.to_owned(),
+            kid: self.current.kid.clone(),
+            cty: "JWT".to_owned(),
+        };
+        let header = b64(serde_json::to_vec(&header)?);
+
+        let mut iv = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut iv)
+            .map_err(|_| anyhow!("error generating session encryption iv"))?;
+
+        let mut ciphertext = plaintext.as_bytes().to_vec();
+        let tag = self
+            .current
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(iv),
+                Aad::from(header.as_bytes()),
+                &mut ciphertext,
+            )
+            .map_err(|_| anyhow!("error encrypting session token"))?;
+
+        // the encrypted key is empty for direct encryption
+        Ok(format!("{}..{}.{}.{}", header, b64(iv), b64(ciphertext), b64(tag)))
+    }
+
+    pub fn decrypt(&self, token: &str) -> Result<String> {
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let (header_b64, iv, ciphertext, tag) = match parts.as_slice() {
+            [header, "", iv, ciphertext, tag] => (*header, *iv, *ciphertext, *tag),
+            _ => anyhow::bail!("token is not a direct encryption JWE"),
+        };
+
+        let header: JweHeader = serde_json::from_slice(&unb64(header_b64)?)?;
+        if header.alg != ALG || header.enc != ENC {
+            anyhow::bail!("unsupported JWE algorithm: {}/{}", header.alg, header.enc);
+        }
+
+        let key = std::iter::once(&self.current)
+            .chain(&self.retired)
+            .find(|key| key.kid == header.kid)
+            .with_context(|| format!("no session encryption key matches kid {:?}", header.kid))?;
+
+        let nonce = Nonce::try_assume_unique_for_key(&unb64(iv)?)
+            .map_err(|_| anyhow!("invalid JWE iv"))?;
+
+        let mut in_out = unb64(ciphertext)?;
+        in_out.extend(unb64(tag)?);
+
+        let plaintext = key
+            .key
+            .open_in_place(nonce, Aad::from(header_b64.as_bytes()), &mut in_out)
+            .map_err(|_| anyhow!("error decrypting session token"))?;
+
+        Ok(String::from_utf8(plaintext.to_vec())?)
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+    RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::Algorithm;
+use simple_asn1::ASN1Block;
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn parse_der(der: &[u8]) -> Result<Vec<ASN1Block>> {
+    simple_asn1::from_der(der).context("error parsing public key")
+}
+
+/// The key bits from a SubjectPublicKeyInfo (`BEGIN PUBLIC KEY`)
+fn spki_key_bits(der: &[u8]) -> Result<Vec<u8>> {
+    match parse_der(der)?.as_slice() {
+        [ASN1Block::Sequence(_, spki)] => match spki.as_slice() {
+            [ASN1Block::Sequence(..), ASN1Block::BitString(_, _, bits)] => Ok(bits.clone()),
+            _ => anyhow::bail!("public key is not a SubjectPublicKeyInfo"),
+        },
+        _ => anyhow::bail!("public key is not a SubjectPublicKeyInfo"),
+    }
+}
+
+/// The modulus and exponent from a PKCS#1 RSAPublicKey
+fn rsa_components(der: &[u8]) -> Result<RSAKeyParameters> {
+    match parse_der(der)?.as_slice() {
+        [ASN1Block::Sequence(_, parts)] => match parts.as_slice() {
+            [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => Ok(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: b64(n.to_bytes_be().1),
+                e: b64(e.to_bytes_be().1),
+            }),
+            _ => anyhow::bail!("public key is not an RSA public key"),
+        },
+        _ => anyhow::bail!("public key is not an RSA public key"),
+    }
+}
+
+fn ec_components(algorithm: Algorithm, point: &[u8]) -> Result<EllipticCurveKeyParameters> {
+    let (curve, len) = match algorithm {
+        Algorithm::ES256 => (EllipticCurve::P256, 32),
+        _ => (EllipticCurve::P384, 48),
+    };
+
+    // only uncompressed points (0x04 || x || y) are supported
+    if point.len() != 1 + 2 * len || point[0] != 4 {
+        anyhow::bail!("public key is not an uncompressed {:?} point", curve);
+    }
+
+    Ok(EllipticCurveKeyParameters {
+        key_type: EllipticCurveKeyType::EC,
+        curve,
+        x: b64(&point[1..=len]),
+        y: b64(&point[len + 1..]),
+    })
+}
+
+/// The public JWK for a session verification key. HMAC keys are secret so
+/// they are never published and `None` is returned for them.
+pub(super) fn public_jwk(kid: &str, algorithm: Algorithm, data: &[u8]) -> Result<Option<Jwk>> {
+    let params = match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => return Ok(None),
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => {
+            let pem = pem::parse(data)?;
+            if pem.tag == "RSA PUBLIC KEY" {
+                AlgorithmParameters::RSA(rsa_components(&pem.contents)?)
+            } else {
+                AlgorithmParameters::RSA(rsa_components(&spki_key_bits(&pem.contents)?)?)
+            }
+        }
+        Algorithm::ES256 | Algorithm::ES384 => {
+            let pem = pem::parse(data)?;
+            let point = spki_key_bits(&pem.contents)?;
+            AlgorithmParameters::EllipticCurve(ec_components(algorithm, &point)?)
+        }
+        Algorithm::EdDSA => {
+            let pem = pem::parse(data)?;
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: b64(spki_key_bits(&pem.contents)?),
+            })
+        }
+    };
+
+    Ok(Some(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            algorithm: Some(algorithm),
+            key_id: Some(kid.to_owned()),
+            ..Default::default()
+        },
+        algorithm: params,
+    }))
+}
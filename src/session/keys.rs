@@ -0,0 +1,259 @@
+use crate::config::Session;
+use crate::session::encryption::Encryption;
+use crate::session::jwk::public_jwk;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use tracing::debug;
+
+fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    )
+}
+
+fn read_key_file(file: &str) -> Result<Vec<u8>> {
+    std::fs::read(file).with_context(|| format!("error reading session key file: {}", file))
+}
+
+/// HMAC secrets are often written with a trailing newline by `echo` or
+/// editors, which is never intended to be part of the secret.
+fn read_secret_file(file: &str) -> Result<Vec<u8>> {
+    let mut secret = read_key_file(file)?;
+    while secret.last().is_some_and(u8::is_ascii_whitespace) {
+        secret.pop();
+    }
+
+    if secret.len() < 32 {
+        anyhow::bail!("session secret must be at least 32 bytes: {}", file);
+    }
+
+    Ok(secret)
+}
+
+/// Asymmetric algorithms are verified with the public key file, HMAC
+/// algorithms with the shared secret file.
+fn verifying_file<'a>(
+    algorithm: Algorithm,
+    public_key_file: Option<&'a str>,
+    secret_file: Option<&'a str>,
+) -> Result<&'a str> {
+    if is_hmac(algorithm) {
+        secret_file.with_context(|| format!("secret_file is required for {:?}", algorithm))
+    } else {
+        public_key_file.with_context(|| format!("public_key_file is required for {:?}", algorithm))
+    }
+}
+
+fn encoding_key(algorithm: Algorithm, data: &[u8]) -> Result<EncodingKey> {
+    Ok(match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => EncodingKey::from_secret(data),
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => EncodingKey::from_rsa_pem(data)?,
+        Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(data)?,
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(data)?,
+    })
+}
+
+fn decoding_key(algorithm: Algorithm, data: &[u8]) -> Result<DecodingKey> {
+    Ok(match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => DecodingKey::from_secret(data),
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => DecodingKey::from_rsa_pem(data)?,
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(data)?,
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(data)?,
+    })
+}
+
+pub(super) struct VerifyingKey {
+    pub(super) kid: String,
+    pub(super) algorithm: Algorithm,
+    pub(super) key: DecodingKey,
+    jwk: Option<Jwk>,
+    valid_until: Option<DateTime<Utc>>,
+}
+
+impl VerifyingKey {
+    fn load(
+        kid: Option<&str>,
+        algorithm: Algorithm,
+        file: &str,
+        valid_until: Option<DateTime<Utc>>,
+    ) -> Result<VerifyingKey> {
+        let data = if is_hmac(algorithm) {
+            read_secret_file(file)?
+        } else {
+            read_key_file(file)?
+        };
+
+        let key = decoding_key(algorithm, &data)
+            .with_context(|| format!("error loading {:?} session key: {}", algorithm, file))?;
+
+        let kid = match kid {
+            Some(kid) => kid.to_owned(),
+            // a digest of the secret would be published in every token header
+            None if is_hmac(algorithm) => {
+                anyhow::bail!("key_id is required for {:?} session keys: {}", algorithm, file)
+            }
+            None => derive_key_id(&data),
+        };
+        let jwk = public_jwk(&kid, algorithm, &data)
+            .with_context(|| format!("error reading {:?} session public key: {}", algorithm, file))?;
+
+        Ok(VerifyingKey {
+            kid,
+            algorithm,
+            key,
+            jwk,
+            valid_until,
+        })
+    }
+
+    fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.valid_until.is_none_or(|until| now < until)
+    }
+}
+
+/// When no `key_id` is configured, the key ID of a public key is derived from
+/// the key file so that every instance sharing the same key stamps the same `kid`.
+fn derive_key_id(data: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    base64::encode_config(&digest.as_ref()[..12], base64::URL_SAFE_NO_PAD)
+}
+
+/// The keys used for session JWTs. New sessions are always signed with the
+/// current key; retired keys are only accepted for verification until their
+/// `valid_until` time passes.
+pub struct KeySet {
+    kid: String,
+    algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verifying_keys: Vec<VerifyingKey>,
+    encryption: Option<Encryption>,
+}
+
+impl KeySet {
+    pub fn from_config(config: &Session) -> Result<KeySet> {
+        let algorithm = config.algorithm;
+
+        let signing_key = if is_hmac(algorithm) {
+            let file = verifying_file(algorithm, None, config.secret_file.as_deref())?;
+            encoding_key(algorithm, &read_secret_file(file)?)?
+        } else {
+            let file = config
+                .private_key_file
+                .as_deref()
+                .with_context(|| format!("private_key_file is required for {:?}", algorithm))?;
+            encoding_key(algorithm, &read_key_file(file)?)
+                .context("error loading session private key")?
+        };
+
+        let file = verifying_file(
+            algorithm,
+            config.public_key_file.as_deref(),
+            config.secret_file.as_deref(),
+        )?;
+        let current = VerifyingKey::load(config.key_id.as_deref(), algorithm, file, None)?;
+        let kid = current.kid.clone();
+
+        let mut verifying_keys = vec![current];
+        let now = Utc::now();
+
+        for retired in &config.retired_keys {
+            let algorithm = retired.algorithm.unwrap_or(algorithm);
+            let file = verifying_file(
+                algorithm,
+                retired.public_key_file.as_deref(),
+                retired.secret_file.as_deref(),
+            )?;
+
+            let key = VerifyingKey::load(
+                retired.key_id.as_deref(),
+                algorithm,
+                file,
+                Some(retired.valid_until),
+            )?;
+
+            if key.kid == kid {
+                anyhow::bail!("retired session key has the same key id as the current key: {}", kid);
+            }
+
+            if key.is_valid(now) {
+                verifying_keys.push(key);
+            } else {
+                debug!(kid = %key.kid, "ignoring expired session key");
+            }
+        }
+
+        let encryption = config
+            .encryption
+            .as_ref()
+            .map(Encryption::from_config)
+            .transpose()?;
+
+        Ok(KeySet {
+            kid,
+            algorithm,
+            signing_key,
+            verifying_keys,
+            encryption,
+        })
+    }
+
+    pub(super) fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        header
+    }
+
+    pub(super) fn signing_key(&self) -> &EncodingKey {
+        &self.signing_key
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The public keys that tokens may currently be verified with, for publishing
+    /// to upstreams. HMAC keys are never included.
+    pub fn jwks(&self) -> JwkSet {
+        let now = Utc::now();
+
+        JwkSet {
+            keys: self
+                .verifying_keys
+                .iter()
+                .filter(|key| key.is_valid(now))
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+
+    pub(super) fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    /// Keys that may have signed a token with the given `kid`. Tokens issued before
+    /// key IDs were introduced have no `kid` and are checked against every valid key.
+    pub(super) fn verifying_keys<'a>(
+        &'a self,
+        kid: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a VerifyingKey> + 'a {
+        let now = Utc::now();
+
+        self.verifying_keys
+            .iter()
+            .filter(move |key| key.is_valid(now))
+            .filter(move |key| kid.is_none_or(|kid| key.kid == kid))
+    }
+}
@@ -0,0 +1,81 @@
+use crate::session::KeySet;
+use anyhow::Result;
+use jsonwebtoken::Validation;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// `typ` of the identity tokens sent to the target, which are signed with the
+/// session key but must never be accepted as a session
+pub const IDENTITY_TYPE: &str = "identity+jwt";
+
+/// Sign claims with the current session key.
+pub fn encode<T: Serialize>(keys: &KeySet, claims: &T) -> Result<String> {
+    Ok(jsonwebtoken::encode(&keys.header(), claims, keys.signing_key())?)
+}
+
+/// Sign identity claims for the target with the current session key.
+pub fn encode_identity<T: Serialize>(keys: &KeySet, claims: &T) -> Result<String> {
+    let mut header = keys.header();
+    header.typ = Some(IDENTITY_TYPE.to_owned());
+    Ok(jsonwebtoken::encode(&header, claims, keys.signing_key())?)
+}
+
+/// Verify a token against the session keys and check it was issued for `audience`.
+/// Only keys whose algorithm matches the token header are tried, so a token can't
+/// choose how it is verified.
+pub fn decode<T: DeserializeOwned>(keys: &KeySet, token: &str, audience: &str) -> Result<T> {
+    let header = jsonwebtoken::decode_header(token)?;
+
+    if header.typ.as_deref() == Some(IDENTITY_TYPE) {
+        anyhow::bail!("identity tokens can't be used as a session");
+    }
+
+    let mut result = Err(anyhow::anyhow!("no session key matches kid {:?}", header.kid));
+
+    for key in keys.verifying_keys(header.kid.as_deref()) {
+        if key.algorithm != header.alg {
+            continue;
+        }
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_audience(&[audience]);
+
+        match jsonwebtoken::decode(token, &key.key, &validation) {
+            Ok(data) => return Ok(data.claims),
+            Err(err) => result = Err(err.into()),
+        }
+    }
+
+    result
+}
+
+fn is_encrypted(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+/// Sign claims and, if session encryption is configured, encrypt the signed
+/// token so the claims can't be read by whoever holds it.
+pub fn seal<T: Serialize>(keys: &KeySet, claims: &T) -> Result<String> {
+    let jwt = encode(keys, claims)?;
+
+    match keys.encryption() {
+        Some(encryption) => encryption.encrypt(&jwt),
+        None => Ok(jwt),
+    }
+}
+
+/// Decrypt (if needed) and verify a token produced by `seal`. Once encryption is
+/// configured, signed tokens that aren't encrypted are only accepted until its
+/// `accept_unencrypted_until`.
+pub fn unseal<T: DeserializeOwned>(keys: &KeySet, token: &str, audience: &str) -> Result<T> {
+    match (is_encrypted(token), keys.encryption()) {
+        (true, Some(encryption)) => decode(keys, &encryption.decrypt(token)?, audience),
+        (true, None) => {
+            anyhow::bail!("token is encrypted but session encryption is not configured")
+        }
+        (false, Some(encryption)) if !encryption.accepts_unencrypted() => {
+            anyhow::bail!("token is not encrypted but session encryption is required")
+        }
+        (false, _) => decode(keys, token, audience),
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+use tracing::info;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static STOPPING: Lazy<(watch::Sender<bool>, watch::Receiver<bool>)> =
+    Lazy::new(|| watch::channel(false));
+
+static TUNNELS: AtomicUsize = AtomicUsize::new(0);
+static TUNNEL_CLOSED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Wait for SIGTERM or SIGINT
+pub async fn signal_received() -> Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = term.recv() => info!("received SIGTERM"),
+        res = tokio::signal::ctrl_c() => {
+            res?;
+            info!("received SIGINT");
+        }
+    }
+
+    Ok(())
+}
+
+/// True once shutdown has started, so readiness checks should fail
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+pub fn start_draining() {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Tell listeners to stop accepting connections
+pub fn stop_accepting() {
+    let _ = STOPPING.0.send(true);
+}
+
+/// Resolves once listeners should stop accepting connections
+pub async fn stopping() {
+    let mut rx = STOPPING.1.clone();
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Held for the life of an upgraded connection. Hyper hands these off once
+/// upgraded, so they are counted here to be waited for on shutdown.
+pub struct TunnelGuard(());
+
+pub fn track_tunnel() -> TunnelGuard {
+    TUNNELS.fetch_add(1, Ordering::SeqCst);
+    TunnelGuard(())
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        if TUNNELS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            TUNNEL_CLOSED.notify_waiters();
+        }
+    }
+}
+
+pub fn open_tunnels() -> usize {
+    TUNNELS.load(Ordering::SeqCst)
+}
+
+/// Resolves once every upgraded connection has closed
+pub async fn tunnels_closed() {
+    loop {
+        // created before checking the count so a wakeup in between isn't missed
+        let notified = TUNNEL_CLOSED.notified();
+        let open = open_tunnels();
+        if open == 0 {
+            return;
+        }
+
+        info!(open, "waiting for upgraded connections to close");
+        notified.await;
+    }
+}
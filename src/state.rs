@@ -1,4 +1,5 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{Context as _, Result};
@@ -6,48 +7,80 @@ use arc_swap::ArcSwapOption;
 use futures_util::stream::StreamExt;
 use hyper::client::{Client, HttpConnector};
 use hyper::{Body, Request, Response};
-use inotify::{EventOwned, WatchMask};
+use inotify::{EventOwned, Inotify, WatchDescriptor, WatchMask};
 use once_cell::sync::Lazy;
 use tracing::{info, trace, warn};
 
+use crate::access_log::AccessLog;
 use crate::config;
-use crate::config::Config;
+use crate::config::{Config, FilterConf};
+use crate::conn::ConnInfo;
 use crate::filters::{Context, FilterChain};
-use jsonwebtoken::{EncodingKey, DecodingKey};
+use crate::metrics::{self, Routes};
+use crate::session::KeySet;
+use crate::tls::{self, Certificates};
 
 pub static STATE: Lazy<ArcSwapOption<State>> = Lazy::new(ArcSwapOption::empty);
 
 pub struct State {
     pub config: Config,
     pub client: Client<HttpConnector>,
-    pub session_key: EncodingKey,
-    pub session_pub_key: DecodingKey<'static>,
+    pub session_keys: KeySet,
     pub filters: FilterChain,
+    /// chains for listeners with their own filters, by `Listener::name`.
+    /// Listeners are only bound at startup, so the position of a listener in
+    /// a reloaded config says nothing about which socket it belongs to.
+    pub listener_filters: HashMap<String, FilterChain>,
+    pub metric_routes: Routes,
+    pub access_log: Option<AccessLog>,
 }
 
 impl State {
     pub fn from_config(config: Config) -> Result<State> {
         let filters = FilterChain::from_config(&config)?;
 
-        let pem = std::fs::read(&config.session.private_key_file)
-            .context("error loading session private key")?;
-        let session_key = EncodingKey::from_rsa_pem(pem.as_ref())?;
+        let listener_filters = config
+            .server
+            .listeners
+            .iter()
+            .filter_map(|listener| {
+                let chain = FilterChain::from_filters(listener.filters.as_deref()?);
+                Some(chain.map(|chain| (listener.name().to_string(), chain)))
+            })
+            .collect::<Result<_>>()?;
 
-        let pem = std::fs::read(&config.session.public_key_file)
-            .context("error reading session public key file")?;
-        let session_pub_key = DecodingKey::from_rsa_pem(pem.as_ref())?.into_static();
+        let session_keys = KeySet::from_config(&config.session)?;
+
+        let metric_routes = Routes::from_config(config.server.metrics.as_ref())?;
+
+        let access_log = config
+            .server
+            .access_log
+            .as_ref()
+            .map(AccessLog::from_config)
+            .transpose()?;
 
         Ok(State {
             config,
             client: Client::new(),
-            session_key,
-            session_pub_key,
+            session_keys,
             filters,
+            listener_filters,
+            metric_routes,
+            access_log,
         })
     }
 
-    pub async fn handle(&self, req: Request<Body>) -> Result<Response<Body>> {
-        let ctx = Context::new(&self);
+    pub async fn handle(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        crate::target::strip_identity(&mut req, &self.config);
+
+        let chain = req
+            .extensions()
+            .get::<ConnInfo>()
+            .and_then(|conn| self.listener_filters.get(&*conn.listener))
+            .unwrap_or(&self.filters);
+
+        let ctx = Context::new(self, chain);
         ctx.next(req).await
     }
 }
@@ -57,45 +90,203 @@ pub fn init(config_file: impl AsRef<Path>) -> Result<Arc<State>> {
         .context("config file path cannot be resolved")?;
     trace!(?config_file, "config file");
 
-    start_file_watch(&config_file)?;
-    reload_config(&config_file)
+    let state = reload_config(&config_file)?;
+    start_file_watch(config_file, &state.config)?;
+    Ok(state)
+}
+
+/// What a change to a watched file reloads
+#[derive(Clone, Debug, PartialEq)]
+enum Reload {
+    State,
+    /// only the keys of the api_key filters using the file
+    ApiKeys(String),
+    /// only the TLS certificates
+    Certificates,
+}
+
+/// Files other than the config file itself that the state is built from.
+/// A change to most of them reloads the whole state.
+fn watched_files(config: &Config) -> Vec<(&str, Reload)> {
+    let session = &config.session;
+
+    let mut files = vec![
+        &session.private_key_file,
+        &session.public_key_file,
+        &session.secret_file,
+    ];
+    for key in &session.retired_keys {
+        files.push(&key.public_key_file);
+        files.push(&key.secret_file);
+    }
+
+    let mut files: Vec<&str> = files.into_iter().flatten().map(String::as_str).collect();
+
+    if let Some(encryption) = &session.encryption {
+        files.push(&encryption.key_file);
+        files.extend(encryption.retired_keys.iter().map(|key| key.key_file.as_str()));
+    }
+
+    let mut files: Vec<(&str, Reload)> =
+        files.into_iter().map(|file| (file, Reload::State)).collect();
+
+    if let Some(tls) = &config.server.tls {
+        let mut tls_files = vec![tls.cert_file.as_str(), &tls.key_file];
+        tls_files.extend(tls.key_password_file.as_deref());
+        tls_files.extend(tls.ocsp_file.as_deref());
+
+        for cert in &tls.certificates {
+            tls_files.push(&cert.cert_file);
+            tls_files.push(&cert.key_file);
+            tls_files.extend(cert.key_password_file.as_deref());
+            tls_files.extend(cert.ocsp_file.as_deref());
+        }
+
+        files.extend(tls_files.into_iter().map(|file| (file, Reload::Certificates)));
+    }
+
+    let listener_filters = config.server.listeners.iter().filter_map(|l| l.filters.as_ref());
+    for filter in config.filters.iter().chain(listener_filters.flatten()) {
+        if let FilterConf::ApiKey(api_key) = filter {
+            files.push((&api_key.key_file, Reload::ApiKeys(api_key.key_file.clone())));
+        }
+    }
+
+    files
 }
 
-fn start_file_watch(config_file: &Path) -> Result<()> {
-    // TODO - fix these expects
-    let os_config_file = config_file
+/// Resolve the directory of a file but not the file itself, so that a file which
+/// is replaced by a new symlink (eg. a Kubernetes secret volume) is still recognised.
+fn resolve_watch_path(file: &Path) -> Result<PathBuf> {
+    let name = file
         .file_name()
-        .expect("config path has no filename?")
-        .to_owned();
+        .with_context(|| format!("watched path has no filename: {}", file.display()))?;
+
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
 
-    let dir = config_file.parent().expect("config path has no parent?");
-    trace!(?dir, "inotify watch directory");
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("watched directory cannot be resolved: {}", dir.display()))?;
 
-    let mut watch = inotify::Inotify::init()?;
-    watch
-        .add_watch(&dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
-        .context("error adding inotify watch on directory")?;
+    Ok(dir.join(name))
+}
+
+struct FileWatch {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    files: HashMap<PathBuf, Reload>,
+}
+
+impl FileWatch {
+    fn update(&mut self, config_file: &Path, config: &Config) -> Result<()> {
+        let mut files = HashMap::new();
+        files.insert(config_file.to_owned(), Reload::State);
+        for (file, reload) in watched_files(config) {
+            let entry = files
+                .entry(resolve_watch_path(Path::new(file))?)
+                .or_insert_with(|| reload.clone());
+            // a file used for more than one thing reloads everything
+            if *entry != reload {
+                *entry = Reload::State;
+            }
+        }
+
+        for file in files.keys() {
+            let dir = file.parent().expect("resolved path has no parent?");
+
+            if !self.dirs.values().any(|watched| watched == dir) {
+                trace!(?dir, "inotify watch directory");
+
+                let wd = self
+                    .inotify
+                    .add_watch(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+                    .context("error adding inotify watch on directory")?;
+                self.dirs.insert(wd, dir.to_owned());
+            }
+        }
+
+        self.files = files;
+        Ok(())
+    }
+
+    fn reload_for(&self, event: &EventOwned) -> Option<Reload> {
+        match (self.dirs.get(&event.wd), &event.name) {
+            (Some(dir), Some(name)) => self.files.get(&dir.join(name)).cloned(),
+            _ => None,
+        }
+    }
+}
+
+fn start_file_watch(config_file: PathBuf, config: &Config) -> Result<()> {
+    let mut watch = FileWatch {
+        inotify: Inotify::init()?,
+        dirs: HashMap::new(),
+        files: HashMap::new(),
+    };
+    watch.update(&config_file, config)?;
 
     tokio::task::spawn(async move {
         let mut buf = [0; 1024];
-        let mut stream = watch.event_stream(&mut buf)?;
+        let mut stream = watch.inotify.event_stream(&mut buf)?;
 
         while let Some(item) = stream.next().await {
-            match item {
-                Ok(EventOwned {
-                    name: Some(name), ..
-                }) if name == os_config_file => {
-                    warn!("reloading configuration");
-                    match reload_config(&os_config_file) {
-                        Ok(_) => info!("new config loaded successfully"),
-                        Err(err) => warn!(
-                            "new config is not valid, old config has been retained: {}",
-                            err
-                        ),
+            let event = match item {
+                Ok(event) => event,
+                Err(err) => {
+                    warn!("inotify error: {:?}", err);
+                    continue;
+                }
+            };
+
+            match watch.reload_for(&event) {
+                Some(Reload::ApiKeys(file)) => {
+                    info!(file = %file, "reloading api keys");
+                    match crate::filters::reload_key_file(&file) {
+                        Ok(()) => metrics::config_reload(true),
+                        Err(err) => {
+                            warn!("new api keys are not valid, old keys have been retained: {:#}", err);
+                            metrics::config_reload(false);
+                        }
+                    }
+                }
+                Some(Reload::Certificates) => {
+                    info!(file = ?event.name, "reloading tls certificates");
+                    let state = STATE.load_full().expect("state unset?");
+                    let tls = state.config.server.tls.as_ref();
+                    match tls.map(Certificates::from_config).transpose() {
+                        Ok(certs) => {
+                            tls::set_certificates(certs);
+                            metrics::config_reload(true);
+                        }
+                        Err(err) => {
+                            warn!(
+                                "new tls certificates are not valid, old certificates have been retained: {:#}",
+                                err
+                            );
+                            metrics::config_reload(false);
+                        }
+                    }
+                }
+                Some(Reload::State) => {
+                    warn!(file = ?event.name, "reloading configuration");
+                    match reload_config(&config_file) {
+                        Ok(state) => {
+                            info!("new config loaded successfully");
+                            metrics::config_reload(true);
+                            if let Err(err) = watch.update(&config_file, &state.config) {
+                                warn!("error updating watched files: {}", err);
+                            }
+                        }
+                        Err(err) => {
+                            warn!("new config is not valid, old config has been retained: {}", err);
+                            metrics::config_reload(false);
+                        }
                     };
                 }
-                Ok(_) => {}
-                Err(err) => warn!("inotify error: {:?}", err),
+                None => {}
             }
         }
 
@@ -112,8 +303,25 @@ fn start_file_watch(config_file: &Path) -> Result<()> {
 fn reload_config(file: impl AsRef<Path>) -> Result<Arc<State>> {
     let config = config::load(file.as_ref())?;
 
+    let certs = config
+        .server
+        .tls
+        .as_ref()
+        .map(Certificates::from_config)
+        .transpose()?;
+
+    if let Some(old) = STATE.load().as_ref() {
+        let names = |config: &Config| -> Vec<String> {
+            config.server.listeners.iter().map(|l| l.name().to_string()).collect()
+        };
+        if names(&old.config) != names(&config) {
+            warn!("listeners that were added or removed only take effect on restart");
+        }
+    }
+
     let state = Arc::new(State::from_config(config)?);
     STATE.store(Some(state.clone()));
+    tls::set_certificates(certs);
 
     Ok(state)
 }
@@ -1,26 +1,181 @@
-use crate::config::Target;
-use crate::session::Claims;
+use crate::config::{Config, Target};
+use crate::metrics;
+use crate::forwarded::{add_forwarded_headers, client_ip, remove_hop_by_hop};
+use crate::session::{token, Claims, AUDIENCE, SESSION_COOKIE};
+use crate::state::State;
 use anyhow::Result;
-use hyper::{client::HttpConnector, Client, Uri, StatusCode};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{client::HttpConnector, Client, Uri, StatusCode, Version};
 use hyper::{Body, Request, Response, header};
+use serde::Serialize;
 use std::convert::TryInto;
-use tracing::{error, info, trace};
+use std::time::Instant;
+use time::{Duration, OffsetDateTime};
+use tracing::{debug, error, info, trace};
 use crate::upgrade::upgrade;
 
+/// Remove identity headers sent by the client, so that the target only sees
+/// identity headers set by sealproxy. The identity token header is always removed.
+pub fn strip_identity(req: &mut Request<Body>, config: &Config) {
+    let server = &config.server;
+    let token_header = config.target.identity_token.as_ref().map(|conf| conf.header.as_str());
+
+    let spoofed: Vec<HeaderName> = req
+        .headers()
+        .keys()
+        .filter(|name| {
+            let name = name.as_str();
+            server.strip_header_prefixes.iter().any(|prefix| {
+                name.get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            }) || server
+                .strip_headers
+                .iter()
+                .map(String::as_str)
+                .chain(token_header)
+                .any(|header| header.eq_ignore_ascii_case(name))
+        })
+        .cloned()
+        .collect();
+
+    for name in spoofed {
+        debug!(header = %name, "removing identity header from client request");
+        req.headers_mut().remove(name);
+    }
+}
+
+/// Record the authenticated identity on the request. It is sent to the target
+/// by `add_identity` when the request is forwarded.
 pub fn add_header_claims(req: &mut Request<Body>, claims: Claims) -> Result<()> {
-    let headers = req.headers_mut();
-    headers.insert("X-Seal-Username", claims.subject.try_into()?);
-    headers.insert("X-Seal-Mechanism", claims.issuer.try_into()?);
+    req.extensions_mut().insert(claims);
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct IdentityClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+    mech: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    groups: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+/// Send the identity recorded by `add_header_claims` to the target as plain
+/// headers and/or as a short lived JWT signed with the session key.
+pub fn add_identity(req: &mut Request<Body>, state: &State) -> Result<()> {
+    let claims = match req.extensions().get::<Claims>() {
+        Some(claims) => claims.clone(),
+        None => return Ok(()),
+    };
+
+    let target = &state.config.target;
+
+    if target.identity_headers {
+        let headers = req.headers_mut();
+        headers.insert("X-Seal-Username", claims.subject.as_str().try_into()?);
+        headers.insert("X-Seal-Mechanism", claims.issuer.as_str().try_into()?);
+        if !claims.scopes.is_empty() {
+            headers.insert("X-Seal-Scopes", claims.scopes.join(" ").try_into()?);
+        }
+    }
+
+    if let Some(config) = &target.identity_token {
+        let now = OffsetDateTime::now_utc();
+
+        let identity = IdentityClaims {
+            iss: state.config.session.issuer.as_deref().unwrap_or(AUDIENCE),
+            sub: &claims.subject,
+            aud: config.audience.as_deref().unwrap_or_else(|| target.url.as_str()),
+            iat: now.unix_timestamp(),
+            exp: (now + Duration::seconds(config.lifetime)).unix_timestamp(),
+            mech: &claims.issuer,
+            groups: &claims.groups,
+            scope: Some(claims.scopes.join(" ")).filter(|scope| !scope.is_empty()),
+        };
+
+        let jwt = token::encode_identity(&state.session_keys, &identity)?;
+        let name: HeaderName = config.header.parse()?;
+        req.headers_mut().insert(name, jwt.try_into()?);
+    }
+
+    Ok(())
+}
+
+fn strip_session_cookie(req: &mut Request<Body>) -> Result<()> {
+    let mut cookies = vec![];
+
+    for val in req.headers().get_all(header::COOKIE) {
+        let kept: Vec<&str> = val
+            .to_str()?
+            .split(';')
+            .map(str::trim)
+            .filter(|c| c.split('=').next() != Some(SESSION_COOKIE))
+            .collect();
+
+        if !kept.is_empty() {
+            cookies.push(HeaderValue::from_str(&kept.join("; "))?);
+        }
+    }
+
+    req.headers_mut().remove(header::COOKIE);
+    for cookie in cookies {
+        req.headers_mut().append(header::COOKIE, cookie);
+    }
 
     Ok(())
 }
 
-#[tracing::instrument(skip(req, client, target))]
+/// Recorded on a request whose `Authorization` header was used to log in, so
+/// that it is only removed when sealproxy, not the target, consumed it
+pub struct AuthorizationUsed;
+
+/// Remove credentials the target doesn't need, eg. the password from a
+/// Basic login or the session cookie.
+fn remove_credentials(req: &mut Request<Body>, target: &Target) -> Result<()> {
+    if target.strip_authorization && req.extensions().get::<AuthorizationUsed>().is_some() {
+        req.headers_mut().remove(header::AUTHORIZATION);
+    }
+
+    if target.strip_session_cookie {
+        strip_session_cookie(req)?;
+    }
+
+    for name in &target.remove_headers {
+        req.headers_mut().remove(name.as_str());
+    }
+
+    Ok(())
+}
+
+fn rewrite_host(req: &mut Request<Body>, target: &Target) -> Result<()> {
+    if let Some(host) = target.url.host_str() {
+        let host = match target.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        req.headers_mut().insert(header::HOST, host.try_into()?);
+    }
+
+    Ok(())
+}
+
+/// How long the target took to respond, recorded on the response for the access log
+pub struct UpstreamLatency(pub std::time::Duration);
+
+#[tracing::instrument(skip(req, client, config), fields(otel.kind = "client"))]
 pub async fn route(
     mut req: Request<Body>,
     client: &Client<HttpConnector>,
-    target: &Target,
+    config: &Config,
 ) -> Result<Response<Body>> {
+    let target = &config.target;
+
     let path = req.uri().path();
     assert!(path.starts_with("/"));
 
@@ -28,17 +183,50 @@ pub async fn route(
     url.set_query(req.uri().path_and_query().and_then(|pnq| pnq.query()));
     let uri: Uri = url.as_str().parse()?;
 
-    info!(target=%url, "request");
+    let client_ip = client_ip(&req, &config.server.trusted_proxies);
+    info!(target=%url, ?client_ip, "request");
+
+    remove_credentials(&mut req, target)?;
+
+    let is_upgrade = req.headers().contains_key(header::UPGRADE);
+    remove_hop_by_hop(req.headers_mut(), is_upgrade);
+    add_forwarded_headers(&mut req, config)?;
+
+    if target.rewrite_host {
+        rewrite_host(&mut req, target)?;
+    }
+
+    crate::telemetry::inject_context(&mut req);
 
-    if req.headers().contains_key(header::UPGRADE) {
+    let start = Instant::now();
+
+    if is_upgrade {
         trace!("client requested upgrade");
-        upgrade(req, uri, client).await
+        match upgrade(req, uri, client).await {
+            Ok(mut resp) => {
+                metrics::observe_upstream(start.elapsed());
+                resp.extensions_mut().insert(UpstreamLatency(start.elapsed()));
+                Ok(resp)
+            }
+            Err(err) => {
+                metrics::upstream_error("upgrade");
+                Err(err)
+            }
+        }
     } else {
         *req.uri_mut() = uri;
+        // the client only speaks HTTP/1, whatever the incoming connection used
+        *req.version_mut() = Version::HTTP_11;
         let resp = match client.request(req).await {
-            Ok(resp) => resp,
+            Ok(mut resp) => {
+                metrics::observe_upstream(start.elapsed());
+                resp.extensions_mut().insert(UpstreamLatency(start.elapsed()));
+                remove_hop_by_hop(resp.headers_mut(), false);
+                resp
+            }
             Err(err) => {
                 error!("gateway error: {}", err);
+                metrics::upstream_error("request");
                 Response::builder()
                     .status(StatusCode::BAD_GATEWAY)
                     .body(hyper::Body::empty())?
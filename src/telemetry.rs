@@ -0,0 +1,103 @@
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Request};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Sampler};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, KeyValue};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Spans are exported with OTLP (gRPC) when an endpoint is given with the
+/// standard `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`
+/// variables. They are read from the environment rather than the config file
+/// because tracing is set up before the config is loaded.
+pub fn tracer() -> Result<Option<trace::Tracer>> {
+    let enabled = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var_os(var).is_some());
+    if !enabled {
+        return Ok(None);
+    }
+
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "sealproxy".to_owned());
+
+    // a sample rate for new traces, callers that send a traceparent decide for themselves
+    let ratio = std::env::var("OTEL_TRACES_SAMPLER_ARG")
+        .ok()
+        .and_then(|ratio| ratio.parse().ok())
+        .unwrap_or(1.0);
+
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)));
+    let resource = Resource::new(vec![KeyValue::new("service.name", service_name)]);
+
+    let config = trace::config()
+        .with_sampler(sampler)
+        .with_resource(Resource::default().merge(&resource));
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .with_env()
+        .with_trace_config(config)
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(Some(tracer))
+}
+
+/// Send any spans that haven't been exported yet
+pub async fn shutdown() {
+    // the batch exporter blocks until its queue is flushed
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|val| val.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        // an empty `tracestate` shouldn't be sent
+        if value.is_empty() {
+            self.0.remove(key);
+        } else if let (Ok(name), Ok(val)) =
+            (HeaderName::try_from(key), HeaderValue::try_from(value))
+        {
+            self.0.insert(name, val);
+        }
+    }
+}
+
+/// Continue the trace from the client's `traceparent` header, if it sent one
+pub fn extract_context(req: &Request<Body>) {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    tracing::Span::current().set_parent(parent);
+}
+
+/// Replace the `traceparent` header with the current span, so that the target's
+/// spans appear under it. When tracing is off the client's header is left alone.
+pub fn inject_context(req: &mut Request<Body>) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(req.headers_mut()))
+    });
+}
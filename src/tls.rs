@@ -1,8 +1,22 @@
-use crate::config::TlsConfig;
-use anyhow::{Context, Result};
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use crate::config::{ClientAuth, TlsConfig, TlsVersion};
+use crate::metrics;
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use pkcs8::EncryptedPrivateKeyInfo;
+use rustls::sign::{self, CertifiedKey, SigningKey};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+    ClientHello, NoClientAuth, NoServerSessionStorage, PrivateKey, ProtocolVersion,
+    ResolvesServerCert, RootCertStore, ServerConfig, ServerSessionMemoryCache,
+    SupportedCipherSuite, Ticketer, ALL_CIPHERSUITES,
+};
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::BufReader;
+use std::sync::Arc;
+use tracing::{debug, trace};
+use x509_parser::extensions::GeneralName;
 
 fn load_certs(filename: &str) -> Result<Vec<Certificate>> {
     let certfile =
@@ -16,35 +30,307 @@ fn load_certs(filename: &str) -> Result<Vec<Certificate>> {
         .collect())
 }
 
-fn load_private_key(filename: &str) -> Result<PrivateKey> {
-    let keyfile =
-        File::open(filename).context(format!("error opening tls keyfile: {}", filename))?;
+fn read_password(filename: &str) -> Result<String> {
+    let password = std::fs::read_to_string(filename)
+        .with_context(|| format!("error reading tls key password file: {}", filename))?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Decrypt an `ENCRYPTED PRIVATE KEY` (PKCS#8 with PBES2), returning the
+/// plain PKCS#8 key
+fn decrypt_private_key(der: &[u8], password_file: Option<&str>) -> Result<PrivateKey> {
+    let password_file = password_file.context("key_password_file is not set")?;
+
+    let info = EncryptedPrivateKeyInfo::try_from(der)
+        .map_err(|e| anyhow!("error parsing encrypted key: {}", e))?;
+    let key = info
+        .decrypt(read_password(password_file)?)
+        .map_err(|e| anyhow!("error decrypting key, is the password right? {}", e))?;
+
+    Ok(PrivateKey(key.as_bytes().to_vec()))
+}
+
+/// Load the first usable private key in a PEM file. If none can be used, the
+/// error says why each key that was found failed.
+fn load_signing_key(filename: &str, password_file: Option<&str>) -> Result<Box<dyn SigningKey>> {
+    let data = std::fs::read(filename)
+        .with_context(|| format!("error opening tls keyfile: {}", filename))?;
+    let sections = pem::parse_many(data)
+        .with_context(|| format!("error parsing tls keyfile: {}", filename))?;
+
+    let mut failures = vec![];
+    for section in sections {
+        let key = match section.tag.as_str() {
+            "PRIVATE KEY" | "RSA PRIVATE KEY" => Ok(PrivateKey(section.contents)),
+            "ENCRYPTED PRIVATE KEY" => decrypt_private_key(&section.contents, password_file),
+            "EC PRIVATE KEY" => Err(anyhow!(
+                "SEC1 keys aren't supported, convert it to PKCS#8 with `openssl pkcs8 -topk8`"
+            )),
+            _ => continue,
+        };
+
+        let signing_key = key.and_then(|key| {
+            sign::any_supported_type(&key)
+                .map_err(|_| anyhow!("not an RSA, ECDSA (P-256/P-384) or Ed25519 key"))
+        });
+
+        match signing_key {
+            Ok(signing_key) => return Ok(signing_key),
+            Err(err) => failures.push(format!("{}: {:#}", section.tag, err)),
+        }
+    }
+
+    if failures.is_empty() {
+        anyhow::bail!(
+            "no PRIVATE KEY, RSA PRIVATE KEY or ENCRYPTED PRIVATE KEY found in tls keyfile: {}",
+            filename
+        );
+    }
+
+    anyhow::bail!("no usable key in tls keyfile {} ({})", filename, failures.join("; "))
+}
+
+fn load_certified_key(
+    cert_file: &str,
+    key_file: &str,
+    password_file: Option<&str>,
+    ocsp_file: Option<&str>,
+) -> Result<CertifiedKey> {
+    let certs = load_certs(cert_file)?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cert_file);
+    }
+
+    let key = load_signing_key(key_file, password_file)?;
+
+    let mut certified_key = CertifiedKey::new(certs, Arc::new(key));
+
+    if let Some(ocsp_file) = ocsp_file {
+        let ocsp = std::fs::read(ocsp_file)
+            .with_context(|| format!("error reading ocsp response: {}", ocsp_file))?;
+        certified_key.ocsp = Some(ocsp);
+    }
+
+    Ok(certified_key)
+}
 
-    let mut reader = BufReader::new(keyfile);
+/// The DNS names in a certificate's subject alternative names, or its common
+/// name if it has none
+fn certificate_names(cert: &Certificate) -> Result<Vec<String>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| anyhow!("error parsing tls certificate: {}", e))?;
 
-    loop {
-        let item = rustls_pemfile::read_one(&mut reader).context("error parsing tls keyfile")?;
+    let mut names = vec![];
+    if let Some(san) = cert.subject_alternative_name()? {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_ascii_lowercase());
+            }
+        }
+    }
 
-        match item {
-            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(PrivateKey(key)),
-            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(PrivateKey(key)),
-            None => break,
-            _ => {}
+    if names.is_empty() {
+        for cn in cert.subject().iter_common_name() {
+            if let Ok(cn) = cn.as_str() {
+                names.push(cn.to_ascii_lowercase());
+            }
         }
     }
 
-    anyhow::bail!(
-        "no keys found in {:?} (encrypted keys not supported)",
-        filename
-    )
+    Ok(names)
+}
+
+/// The expiry time of a certificate, as a unix timestamp
+fn not_after(cert: &Certificate) -> Result<i64> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| anyhow!("error parsing tls certificate: {}", e))?;
+    Ok(cert.validity().not_after.timestamp())
+}
+
+/// `*.example.com` matches a single label, so `a.example.com` but not `example.com`
+/// or `a.b.example.com`
+fn name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == name,
+    }
+}
+
+/// The certificates for new handshakes. They are kept apart from the `State` so
+/// that a changed certificate file only reloads the certificates.
+static CERTIFICATES: Lazy<ArcSwapOption<Certificates>> = Lazy::new(ArcSwapOption::empty);
+
+/// Use new certificates for handshakes from now on, without dropping connections
+pub fn set_certificates(certs: Option<Certificates>) {
+    metrics::set_cert_expiry(certs.as_ref());
+    CERTIFICATES.store(certs.map(Arc::new));
+}
+
+/// The server certificates, selected by the SNI name sent by the client.
+pub struct Certificates {
+    default: CertifiedKey,
+    by_name: Vec<(Vec<String>, CertifiedKey)>,
+    expiry: Vec<(String, i64)>,
+}
+
+impl Certificates {
+    pub fn from_config(config: &TlsConfig) -> Result<Certificates> {
+        let default = load_certified_key(
+            &config.cert_file,
+            &config.key_file,
+            config.key_password_file.as_deref(),
+            config.ocsp_file.as_deref(),
+        )?;
+        let mut expiry = vec![(config.cert_file.clone(), not_after(&default.cert[0])?)];
+
+        let mut by_name = vec![];
+        for cert in &config.certificates {
+            let key = load_certified_key(
+                &cert.cert_file,
+                &cert.key_file,
+                cert.key_password_file.as_deref(),
+                cert.ocsp_file.as_deref(),
+            )?;
+
+            let names = if cert.server_names.is_empty() {
+                certificate_names(&key.cert[0])?
+            } else {
+                cert.server_names.iter().map(|name| name.to_ascii_lowercase()).collect()
+            };
+
+            debug!(?names, file = %cert.cert_file, "loaded tls certificate");
+            expiry.push((cert.cert_file.clone(), not_after(&key.cert[0])?));
+            by_name.push((names, key));
+        }
+
+        Ok(Certificates {
+            default,
+            by_name,
+            expiry,
+        })
+    }
+
+    /// The expiry time of each certificate by file, as unix timestamps
+    pub fn expiry(&self) -> &[(String, i64)] {
+        &self.expiry
+    }
+
+    fn resolve(&self, server_name: Option<&str>) -> CertifiedKey {
+        let server_name = server_name.map(str::to_ascii_lowercase);
+
+        let found = server_name.as_deref().and_then(|server_name| {
+            self.by_name
+                .iter()
+                .find(|(names, _)| names.iter().any(|pattern| name_matches(pattern, server_name)))
+        });
+
+        match found {
+            Some((_, key)) => key.clone(),
+            None => {
+                trace!(?server_name, "using default tls certificate");
+                self.default.clone()
+            }
+        }
+    }
+}
+
+/// Resolves certificates from those last set with `set_certificates`
+struct CertificatesResolver;
+
+impl ResolvesServerCert for CertificatesResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let server_name: Option<&str> = client_hello.server_name().map(Into::into);
+
+        let certs = CERTIFICATES.load();
+        Some(certs.as_ref()?.resolve(server_name))
+    }
+}
+
+/// A server config that verifies client certificates against `client_ca_file`, if set
+fn new_server_config(tls_config: &TlsConfig) -> Result<ServerConfig> {
+    let ca_file = match &tls_config.client_ca_file {
+        Some(ca_file) => ca_file,
+        None if tls_config.client_auth == ClientAuth::Required => {
+            anyhow::bail!("client_ca_file is required when client_auth is required")
+        }
+        None => return Ok(ServerConfig::new(NoClientAuth::new())),
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_file)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("invalid client CA certificate in {}", ca_file))?;
+    }
+
+    Ok(ServerConfig::new(match tls_config.client_auth {
+        ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+    }))
+}
+
+fn protocol_versions(min_version: TlsVersion) -> Vec<ProtocolVersion> {
+    match min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    }
+}
+
+fn cipher_suites(
+    names: &[String],
+    versions: &[ProtocolVersion],
+) -> Result<Vec<&'static SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(ALL_CIPHERSUITES.to_vec());
+    }
+
+    let mut suites = vec![];
+    for name in names {
+        let suite = ALL_CIPHERSUITES
+            .iter()
+            .find(|suite| format!("{:?}", suite.suite).eq_ignore_ascii_case(name))
+            .with_context(|| format!("unsupported tls cipher suite: {}", name))?;
+        suites.push(*suite);
+    }
+
+    for version in versions {
+        if !suites.iter().any(|suite| suite.usable_for_version(*version)) {
+            anyhow::bail!("no configured cipher suites can be used with {:?}", version);
+        }
+    }
+
+    Ok(suites)
+}
+
+fn alpn_protocols(names: &[String]) -> Result<Vec<Vec<u8>>> {
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            "h2" | "http/1.1" => Ok(name.as_bytes().to_vec()),
+            _ => anyhow::bail!("unsupported alpn protocol: {}", name),
+        })
+        .collect()
 }
 
 pub fn get_server_tls_config(tls_config: &TlsConfig) -> Result<ServerConfig> {
-    let certs = load_certs(&tls_config.cert_file)?;
-    let key = load_private_key(&tls_config.key_file)?;
+    let mut server_config = new_server_config(tls_config)?;
+    server_config.cert_resolver = Arc::new(CertificatesResolver);
+
+    server_config.versions = protocol_versions(tls_config.min_version);
+    server_config.ciphersuites = cipher_suites(&tls_config.cipher_suites, &server_config.versions)?;
+    server_config.set_protocols(&alpn_protocols(&tls_config.alpn_protocols)?);
 
-    let mut server_config = ServerConfig::new(rustls::NoClientAuth::new());
-    server_config.set_single_cert(certs, key)?;
+    server_config.session_storage = if tls_config.session_cache {
+        ServerSessionMemoryCache::new(1024)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+
+    if tls_config.session_tickets {
+        server_config.ticketer = Ticketer::new();
+    }
 
     Ok(server_config)
 }
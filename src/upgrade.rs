@@ -25,7 +25,9 @@ pub async fn upgrade(req: Request<Body>, uri: Uri, client: &Client<HttpConnector
     let client_upgraded = hyper::upgrade::on(resp).await?;
 
     // upgrade our response to the client - async; it won't resolve until we respond
-    tokio::task::spawn(async {
+    let tunnel = crate::shutdown::track_tunnel();
+    tokio::task::spawn(async move {
+        let _tunnel = tunnel;
         match do_handle_upgrade(req, client_upgraded).await {
             Ok(_) =>  trace!("upgraded connection ended"),
             Err(e) => warn!("error on upgraded connection: {:?}", e),
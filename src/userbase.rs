@@ -8,7 +8,8 @@ use crate::userbase::ldap::Ldap;
 
 #[derive(Debug)]
 pub enum LookupResult {
-    Success,
+    /// the user's password was correct, and they are in these groups
+    Success { groups: Vec<String> },
     NoSuchUser,
     IncorrectPassword,
     Other(String),
@@ -19,11 +20,19 @@ pub type DynUserBase = dyn UserBase + Send + Sync + 'static;
 #[async_trait::async_trait]
 pub trait UserBase {
     async fn lookup(&self, user: &str, password: &str) -> Result<LookupResult>;
+
+    /// The kind of user base, for metrics
+    fn kind(&self) -> &'static str;
+
+    /// Check that the user base can be reached, for readiness checks
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub fn get_user_base(conf: &UserBaseConf) -> Result<Box<DynUserBase>> {
     Ok(match conf {
-        UserBaseConf::Ldap(conf) => Ldap::new(conf)?,
+        UserBaseConf::Ldap(conf) => Box::new(Ldap::new(conf)?),
         UserBaseConf::UserPass(conf) => Box::new(UserPass::new(conf)),
     })
 }
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use crate::config::LdapConf;
-use crate::userbase::{DynUserBase, LookupResult, UserBase};
+use crate::userbase::{LookupResult, UserBase};
 use ldap3::SearchEntry;
 use url::Url;
 
@@ -8,21 +8,33 @@ pub struct Ldap {
     url: Url,
     user_attr: String,
     base_dn: String,
+    group_attr: String,
+    group_base_dn: Option<String>,
+    group_filter: String,
+}
+
+/// The name of a group from its DN, ie. the value of the first RDN
+fn group_name(dn: &str) -> &str {
+    let rdn = dn.split(',').next().unwrap_or(dn);
+    rdn.split_once('=').map_or(rdn, |(_attr, value)| value).trim()
 }
 
 impl Ldap {
-    pub fn new(config: &LdapConf) -> Result<Box<DynUserBase>> {
-        Ok(Box::new(Ldap {
+    pub fn new(config: &LdapConf) -> Result<Ldap> {
+        Ok(Ldap {
             url: config.url.clone(),
             user_attr: config.user_attr.clone().unwrap_or("uid".into()),
             base_dn: config.base_dn.clone(),
-        }))
+            group_attr: config.group_attr.clone().unwrap_or("memberOf".into()),
+            group_base_dn: config.group_base_dn.clone(),
+            group_filter: config.group_filter.clone(),
+        })
     }
 }
 
 #[async_trait::async_trait]
 impl UserBase for Ldap {
-    #[tracing::instrument(skip(self, user, password))]
+    #[tracing::instrument(name = "ldap lookup", skip(self, user, password), fields(url = %self.url))]
     async fn lookup(&self, user: &str, password: &str) -> anyhow::Result<LookupResult> {
         let (conn, mut ldap) = ldap3::LdapConnAsync::from_url(&self.url).await?;
 
@@ -30,29 +42,59 @@ impl UserBase for Ldap {
 
         let query = format!("{}={}", self.user_attr, user);
 
-        let (data, _) = ldap.search(&self.base_dn,
-                                 ldap3::Scope::OneLevel, &query, &["*"]).await?
+        let (mut data, _) = ldap.search(&self.base_dn,
+                                 ldap3::Scope::OneLevel, &query, &["*", &self.group_attr]).await?
             .success()?;
 
-        match data.len() {
+        let result = match data.len() {
             0 => return Ok(LookupResult::NoSuchUser),
-            1 => (),
+            1 => data.remove(0),
             _ => return Ok(LookupResult::Other("user lookup returned more than one user".to_owned()))
         };
 
-        for result in data {
-            let parsed = SearchEntry::construct(result);
-            let user_dn = parsed.dn;
+        let mut parsed = SearchEntry::construct(result);
+        let user_dn = parsed.dn;
+        // attribute names aren't case sensitive
+        let mut group_dns: Vec<String> = parsed.attrs.drain()
+            .filter(|(attr, _)| attr.eq_ignore_ascii_case(&self.group_attr))
+            .flat_map(|(_, values)| values)
+            .collect();
 
-            let result = ldap.simple_bind(&user_dn, password).await?;
+        let result = ldap.simple_bind(&user_dn, password).await?;
+
+        match result.rc {
+            0 => (),
+            49 => return Ok(LookupResult::IncorrectPassword),
+            _ => return Ok(LookupResult::Other(format!("error from LDAP bind: {}", result)))
+        }
 
-            return Ok(match result.rc {
-                0 => LookupResult::Success,
-                49 => LookupResult::IncorrectPassword,
-                _ => LookupResult::Other(format!("error from LDAP bind: {}", result))
-            });
+        // searched as the user, who can generally see their own groups
+        if let Some(group_base_dn) = &self.group_base_dn {
+            let filter = self.group_filter.replace("{dn}", &ldap3::ldap_escape(&user_dn));
+            let (entries, _) = ldap.search(group_base_dn,
+                                           ldap3::Scope::Subtree, &filter, &["1.1"]).await?
+                .success()?;
+            group_dns.extend(entries.into_iter().map(|entry| SearchEntry::construct(entry).dn));
         }
 
-        unreachable!()
+        let mut groups: Vec<String> = group_dns.iter().map(|dn| group_name(dn).to_owned()).collect();
+        groups.sort();
+        groups.dedup();
+
+        Ok(LookupResult::Success { groups })
+    }
+
+    fn kind(&self) -> &'static str {
+        "ldap"
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn check(&self) -> anyhow::Result<()> {
+        let (conn, mut ldap) = ldap3::LdapConnAsync::from_url(&self.url).await?;
+
+        ldap3::drive!(conn);
+
+        ldap.unbind().await?;
+        Ok(())
     }
 }
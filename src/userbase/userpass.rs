@@ -5,33 +5,52 @@ use tracing::debug;
 
 pub struct UserPass {
     users: HashMap<String, String>,
+    groups: HashMap<String, Vec<String>>,
 }
 
 impl UserPass {
     pub fn new(config: &UserPassConf) -> UserPass {
+        // the config lists the members of each group, lookups want the reverse
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        for (group, members) in &config.groups {
+            for member in members {
+                groups.entry(member.clone()).or_default().push(group.clone());
+            }
+        }
+        for user_groups in groups.values_mut() {
+            user_groups.sort();
+        }
+
         UserPass {
             users: config.users.iter().cloned().collect(),
+            groups,
         }
     }
 }
 
 #[async_trait::async_trait]
 impl UserBase for UserPass {
-    #[tracing::instrument(skip(self, user, password))]
+    #[tracing::instrument(name = "user_pass lookup", skip(self, user, password))]
     async fn lookup(&self, user: &str, password: &str) -> anyhow::Result<LookupResult> {
-        return match self.users.get(user) {
+        match self.users.get(user) {
             None => {
                 debug!("user not found");
                 Ok(LookupResult::NoSuchUser)
             }
             Some(expected) if password == expected => {
                 debug!("successful user lookup");
-                Ok(LookupResult::Success)
+                Ok(LookupResult::Success {
+                    groups: self.groups.get(user).cloned().unwrap_or_default(),
+                })
             }
             Some(_) => {
                 debug!("incorrect password");
                 Ok(LookupResult::IncorrectPassword)
             }
-        };
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        "user_pass"
     }
 }